@@ -1,5 +1,7 @@
 use divan::Bencher;
 use ipc::cpu_warmup;
+use ipc::futex::{WaitStrategy, DEFAULT_ADAPTIVE_SPINS};
+use ipc::transport::TransportConfig;
 
 // This affects the number cycles of to execute each method for. In the Divan output, the
 // time per function will be displayed for the total number of cycles, but the throughput
@@ -16,20 +18,50 @@ fn main() {
 #[divan::bench(args = LENS)]
 fn stdin_stdout(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut pipe_runner = ipc::pipes::PipeRunner::new(data_size * KB);
+    let mut pipe_runner =
+        ipc::pipes::PipeRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
 
     bencher
         .counter(n)
-        .bench_local(move || pipe_runner.run(n, false));
+        .bench_local(move || pipe_runner.run(n, false).unwrap());
+}
+
+#[divan::bench(args = LENS)]
+fn named_pipes(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut fifo_runner =
+        ipc::fifo::FifoRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(n)
+        .bench_local(move || fifo_runner.run(n, false).unwrap());
+}
+
+#[divan::bench(args = LENS)]
+fn stdin_stdout_mixed_sizes(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let config = TransportConfig::new(data_size * KB).mixed_sizes(true);
+    let mut pipe_runner = ipc::pipes::PipeRunner::new(true, config).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(n)
+        .bench_local(move || pipe_runner.run(n, false).unwrap());
 }
 
 #[divan::bench(args = LENS)]
 fn tcp_nodelay(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut tcp_runner = ipc::tcp::TcpRunner::new(true, true, data_size * KB);
+    let mut tcp_runner =
+        ipc::tcp::TcpRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -37,14 +69,34 @@ fn tcp_nodelay(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            tcp_runner.run(n, false);
+            tcp_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn tcp_yesdelay(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut tcp_runner = ipc::tcp::TcpRunner::new(true, false, data_size * KB);
+    let mut tcp_runner = ipc::tcp::TcpRunner::new(
+        true,
+        TransportConfig::new(data_size * KB).tcp_nodelay(false),
+    )
+    .unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            tcp_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn tcp_mixed_sizes(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let config = TransportConfig::new(data_size * KB).mixed_sizes(true);
+    let mut tcp_runner = ipc::tcp::TcpRunner::new(true, config).unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -52,14 +104,15 @@ fn tcp_yesdelay(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            tcp_runner.run(n, false);
+            tcp_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn udp(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut udp_runner = ipc::udp::UdpRunner::new(true, data_size * KB);
+    let mut udp_runner =
+        ipc::udp::UdpRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -67,14 +120,118 @@ fn udp(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            udp_runner.run(n, false);
+            udp_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn shared_memory(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut shmem_runner = ipc::shmem::ShmemRunner::new(true, data_size * KB);
+    let mut shmem_runner =
+        ipc::shmem::ShmemRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            shmem_runner.run(n, false).unwrap();
+        });
+}
+
+// shared_memory spins while it waits, these spin for a while and then sleep on the event's futex,
+// or sleep straight away
+#[divan::bench(args = LENS)]
+fn shared_memory_adaptive(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let config = TransportConfig::new(data_size * KB)
+        .wait_strategy(WaitStrategy::Adaptive(DEFAULT_ADAPTIVE_SPINS));
+    let mut shmem_runner = ipc::shmem::ShmemRunner::new(true, config).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            shmem_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn shared_memory_block(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let config = TransportConfig::new(data_size * KB).wait_strategy(WaitStrategy::Block);
+    let mut shmem_runner = ipc::shmem::ShmemRunner::new(true, config).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            shmem_runner.run(n, false).unwrap();
+        });
+}
+
+// Sleeps on eventfds rather than spinning like shared_memory, so compare the two for what the
+// latency costs. Runs of the binary print how much CPU each side used
+#[divan::bench(args = LENS)]
+fn shared_memory_eventfd(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut shmem_runner =
+        ipc::shmem_eventfd::ShmemEventFdRunner::new(true, TransportConfig::new(data_size * KB))
+            .unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            shmem_runner.run(n, false).unwrap();
+        });
+}
+
+// The System V methods, to put numbers on moving off them
+#[divan::bench(args = LENS)]
+fn sysv_shared_memory(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut sysv_runner =
+        ipc::sysv_shm::SysvShmRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            sysv_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn sysv_message_queue(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut sysv_runner =
+        ipc::sysv_msg::SysvMsgRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            sysv_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn shared_memory_ring(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut ring_runner =
+        ipc::shmem_ring::ShmemRingRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -82,14 +239,48 @@ fn shared_memory(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            shmem_runner.run(n, false);
+            ring_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn shared_memory_ring_streaming(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let config = TransportConfig::new(data_size * KB).streaming(true);
+    let mut ring_runner = ipc::shmem_ring::ShmemRingRunner::new(true, config).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            ring_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn memory_mapped_file(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut mmap_runner = ipc::mmap::MmapRunner::new(true, data_size * KB);
+    let mut mmap_runner =
+        ipc::mmap::MmapRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            mmap_runner.run(n, false).unwrap();
+        });
+}
+
+// Messages above msgsize_max, 8KB by default, are split over several queue messages
+#[divan::bench(args = LENS)]
+fn posix_message_queue(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut mqueue_runner =
+        ipc::mqueue::MqueueRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -97,14 +288,16 @@ fn memory_mapped_file(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            mmap_runner.run(n, false);
+            mqueue_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn unix_stream(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut unix_tcp_runner = ipc::unix_stream::UnixStreamRunner::new(true, data_size * KB);
+    let mut unix_tcp_runner =
+        ipc::unix_stream::UnixStreamRunner::new(true, TransportConfig::new(data_size * KB))
+            .unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -112,14 +305,32 @@ fn unix_stream(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            unix_tcp_runner.run(n, false);
+            unix_tcp_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn unix_stream_mixed_sizes(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let config = TransportConfig::new(data_size * KB).mixed_sizes(true);
+    let mut unix_tcp_runner = ipc::unix_stream::UnixStreamRunner::new(true, config).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            unix_tcp_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn unix_datagram(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut unix_udp_runner = ipc::unix_datagram::UnixDatagramRunner::new(true, data_size * KB);
+    let mut unix_udp_runner =
+        ipc::unix_datagram::UnixDatagramRunner::new(true, TransportConfig::new(data_size * KB))
+            .unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -127,14 +338,99 @@ fn unix_datagram(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            unix_udp_runner.run(n, false);
+            unix_udp_runner.run(n, false).unwrap();
         });
 }
 
 #[divan::bench(args = LENS)]
 fn iceoryx(bencher: Bencher, data_size: usize) {
     let n = N;
-    let mut unix_udp_runner = ipc::iceoryx::IceoryxRunner::new(true, data_size * KB);
+    let mut unix_udp_runner =
+        ipc::iceoryx::IceoryxRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            unix_udp_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn stdin_stdout_async(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut pipe_runner =
+        ipc::pipes::AsyncPipeRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            pipe_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn tcp_nodelay_async(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut tcp_runner =
+        ipc::tcp::AsyncTcpRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            tcp_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn udp_async(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut udp_runner =
+        ipc::udp::AsyncUdpRunner::new(true, TransportConfig::new(data_size * KB)).unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            udp_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn unix_stream_async(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut unix_tcp_runner =
+        ipc::unix_stream::AsyncUnixStreamRunner::new(true, TransportConfig::new(data_size * KB))
+            .unwrap();
+
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
+
+    bencher
+        .counter(divan::counter::ItemsCount::new(n))
+        .bench_local(move || {
+            unix_tcp_runner.run(n, false).unwrap();
+        });
+}
+
+#[divan::bench(args = LENS)]
+fn unix_datagram_async(bencher: Bencher, data_size: usize) {
+    let n = N;
+    let mut unix_udp_runner = ipc::unix_datagram::AsyncUnixDatagramRunner::new(
+        true,
+        TransportConfig::new(data_size * KB),
+    )
+    .unwrap();
 
     core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
     cpu_warmup();
@@ -142,6 +438,6 @@ fn iceoryx(bencher: Bencher, data_size: usize) {
     bencher
         .counter(divan::counter::ItemsCount::new(n))
         .bench_local(move || {
-            unix_udp_runner.run(n, false);
+            unix_udp_runner.run(n, false).unwrap();
         });
 }
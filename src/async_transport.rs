@@ -0,0 +1,235 @@
+// Async counterparts of the blocking transports, running on a single threaded tokio runtime so
+// we can see how much latency the runtime adds. They speak the same protocol as the blocking
+// transports they mirror, so they talk to the same consumers.
+
+use crate::error::{IpcError, Result};
+use crate::transport::{
+    check_config, request_sizes, stop_consumer, Endpoint, Transport, TransportConfig,
+    READY_POLL_INTERVAL,
+};
+use crate::{cpu_time, get_payload, ExecutionResult, KB};
+use std::future::Future;
+use std::pin::pin;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// Async version of [`Transport`]. Producer setup follows the same listen, spawn, accept steps.
+// The runner drives these on a current thread runtime, so the futures don't need to be Send
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport: Sized {
+    /// The blocking transport this mirrors, whose consumer we talk to
+    type Blocking: Transport;
+
+    /// Producer-side state which exists before the consumer is running
+    type Listener;
+
+    /// Label used when printing results
+    fn name(config: &TransportConfig) -> String {
+        format!("Async {}", Self::Blocking::name(config))
+    }
+
+    async fn listen(config: &TransportConfig) -> Result<Self::Listener>;
+
+    /// What the consumer needs to reach the listener
+    fn endpoint(listener: &Self::Listener) -> Result<<Self::Blocking as Transport>::Endpoint>;
+
+    /// Any other setup the consumer's process needs, such as piped stdio
+    fn prepare_consumer(_listener: &Self::Listener, _command: &mut Command) -> Result<()> {
+        Ok(())
+    }
+
+    /// Wait up to `config.ready_timeout` for the consumer's ready signal, then finish connecting
+    async fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self>;
+
+    /// Consumer side, `endpoint` is what [`AsyncTransport::endpoint`] gave the producer
+    async fn connect(
+        config: &TransportConfig,
+        endpoint: &<Self::Blocking as Transport>::Endpoint,
+    ) -> Result<Self>;
+
+    async fn send(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Wait until a whole message has arrived. Must be cancel safe, keeping anything it has read
+    /// of a message in `self`, since [`AsyncTransport::recv_timeout`] drops it when time is up
+    async fn recv(&mut self) -> Result<&[u8]>;
+
+    /// Send, failing with [`IpcError::Timeout`] if it hasn't finished within `timeout`
+    async fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.send(data))
+                .await
+                .unwrap_or(Err(IpcError::Timeout)),
+            None => self.send(data).await,
+        }
+    }
+
+    /// Wait up to `timeout` for a whole message, failing with [`IpcError::Timeout`] if none
+    /// arrives. Part of a message which has arrived is kept for the next call
+    async fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.recv())
+                .await
+                .unwrap_or(Err(IpcError::Timeout)),
+            None => self.recv().await,
+        }
+    }
+
+    /// Ask the consumer to stop, the same way as [`Transport::shutdown`]
+    async fn shutdown(&mut self) -> Result<()> {
+        self.send(&[]).await
+    }
+
+    /// Counters to print after a run, as with [`Transport::report`]
+    fn report(&self) -> Option<String> {
+        None
+    }
+
+    /// Release anything the transport left behind, such as socket files
+    fn close(&mut self) {}
+}
+
+pub struct AsyncRunner<T: AsyncTransport> {
+    child_proc: Option<Child>,
+    transport: T,
+    // Declared after the transport so it's dropped after it, the sockets are registered with it
+    runtime: Runtime,
+    shutdown_timeout: Duration,
+    io_timeout: Option<Duration>,
+    name: String,
+    data_size: usize,
+    sizes: Vec<usize>,
+    request_data: Vec<u8>,
+    response_data: Vec<u8>,
+}
+
+impl<T: AsyncTransport> AsyncRunner<T> {
+    pub fn new(start_child: bool, config: TransportConfig) -> Result<Self> {
+        check_config::<T::Blocking>(&config)?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let mut child_proc = None;
+        let transport = runtime.block_on(async {
+            let listener = T::listen(&config).await?;
+            if start_child {
+                let mut command = crate::consumer_command(<T::Blocking as Transport>::CONSUMER);
+                command.args(config.to_args());
+                command.args(T::endpoint(&listener)?.to_args());
+                T::prepare_consumer(&listener, &mut command)?;
+                child_proc = Some(command.spawn()?);
+            }
+            T::accept(listener, &config, child_proc.as_mut()).await
+        });
+        let transport = match transport {
+            Ok(transport) => transport,
+            Err(e) => {
+                if let Some(ref mut child) = child_proc {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(e);
+            }
+        };
+
+        let (request_data, response_data) = get_payload(config.data_size);
+        let mut name = T::name(&config);
+        let sizes = request_sizes(&config, &mut name);
+
+        Ok(Self {
+            child_proc,
+            transport,
+            runtime,
+            shutdown_timeout: config.shutdown_timeout,
+            io_timeout: config.io_timeout,
+            name,
+            data_size: config.data_size,
+            sizes,
+            request_data,
+            response_data,
+        })
+    }
+
+    pub fn run(&mut self, n: usize, print: bool) -> Result<()> {
+        let Self {
+            transport,
+            runtime,
+            sizes,
+            request_data,
+            response_data,
+            io_timeout,
+            ..
+        } = self;
+        // Only the loop is timed, not entering the runtime
+        let (elapsed, cpu) = runtime.block_on(async {
+            let start = Instant::now();
+            let cpu_start = cpu_time();
+            for i in 0..n {
+                let size = sizes[i % sizes.len()];
+                transport
+                    .send_timeout(&request_data[..size], *io_timeout)
+                    .await?;
+                let response = transport.recv_timeout(*io_timeout).await?;
+
+                debug_assert!(
+                    !<T::Blocking as Transport>::RELIABLE || response == &response_data[..size],
+                    "Sent request didn't get response"
+                );
+            }
+            Ok::<_, IpcError>((start.elapsed(), cpu_time() - cpu_start))
+        })?;
+        if print {
+            let res = ExecutionResult::new(
+                format!("{} - {}KB", self.name, self.data_size / KB),
+                elapsed,
+                n,
+            )
+            .cpu(cpu);
+            res.print_info();
+            if let Some(report) = self.transport.report() {
+                println!("\t{}", report);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncTransport> Drop for AsyncRunner<T> {
+    fn drop(&mut self) {
+        let _ = self.runtime.block_on(self.transport.shutdown());
+        if let Some(ref mut child) = self.child_proc {
+            stop_consumer(child, self.shutdown_timeout);
+        }
+        self.transport.close();
+    }
+}
+
+/// Async version of [`crate::transport::wait_for_consumer`], resolving with whatever `ready`
+/// produces once the consumer has signalled
+pub(crate) async fn wait_for_consumer_async<R>(
+    mut child: Option<&mut Child>,
+    timeout: Duration,
+    ready: impl Future<Output = Result<R>>,
+) -> Result<R> {
+    let start = Instant::now();
+    let mut ready = pin!(ready);
+    loop {
+        if let Ok(result) = tokio::time::timeout(READY_POLL_INTERVAL, &mut ready).await {
+            return result;
+        }
+        if let Some(child) = child.as_deref_mut() {
+            if child.try_wait()?.is_some() {
+                return Err(IpcError::PeerGone);
+            }
+        }
+        if start.elapsed() > timeout {
+            return Err(IpcError::Timeout);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// Bounding blocking calls. A call's timeout is turned into a deadline when it starts, so a message
+// which takes several reads or writes is bounded as a whole rather than per read.
+
+use crate::error::{IpcError, Result};
+use std::io::{self, IoSlice, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::time::{Duration, Instant};
+
+pub(crate) fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+/// Fails with [`IpcError::Timeout`] once `deadline` has passed
+pub(crate) fn check(deadline: Option<Instant>) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(IpcError::Timeout),
+        _ => Ok(()),
+    }
+}
+
+/// Time left before `deadline`, failing with [`IpcError::Timeout`] if there's none
+pub(crate) fn remaining(deadline: Option<Instant>) -> Result<Option<Duration>> {
+    match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(Some(left)),
+            _ => Err(IpcError::Timeout),
+        },
+        None => Ok(None),
+    }
+}
+
+/// For a call which has already taken part of a message: running out of time now is a protocol
+/// error rather than [`IpcError::Timeout`], as trying again would start from the middle of the
+/// message
+pub(crate) fn partway(e: IpcError) -> IpcError {
+    match e {
+        IpcError::Timeout => {
+            IpcError::protocol("Timed out partway through a message, the channel is out of step")
+        }
+        e => e,
+    }
+}
+
+// Block until `fd` is ready for `events`. Returns an io error so it can be used from `Read` and
+// `Write` impls, running out of time is `TimedOut` which converts to `IpcError::Timeout`
+fn wait_ready(
+    fd: BorrowedFd<'_>,
+    events: libc::c_short,
+    deadline: Option<Instant>,
+) -> io::Result<()> {
+    let Some(deadline) = deadline else {
+        return Ok(());
+    };
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        // Round up, so we never wake a little early and spin on a zero timeout
+        let millis = left.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+        let mut poll_fd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll_fd, 1, millis) } {
+            0 => return Err(io::ErrorKind::TimedOut.into()),
+            // Errors and hangups are reported by the read or write which follows
+            n if n > 0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn wait_readable(fd: BorrowedFd<'_>, deadline: Option<Instant>) -> Result<()> {
+    Ok(wait_ready(fd, libc::POLLIN, deadline)?)
+}
+
+pub(crate) fn wait_writable(fd: BorrowedFd<'_>, deadline: Option<Instant>) -> Result<()> {
+    Ok(wait_ready(fd, libc::POLLOUT, deadline)?)
+}
+
+/// A stream which waits for its fd to be ready, up to a deadline, before each read or write. With
+/// no deadline it adds nothing, so the untimed path costs the same as the bare stream.
+pub(crate) struct WithDeadline<'a, S: ?Sized> {
+    stream: &'a mut S,
+    deadline: Option<Instant>,
+}
+
+impl<'a, S: ?Sized> WithDeadline<'a, S> {
+    pub(crate) fn new(stream: &'a mut S, timeout: Option<Duration>) -> Self {
+        Self {
+            stream,
+            deadline: deadline(timeout),
+        }
+    }
+}
+
+impl<S: Read + AsFd + ?Sized> Read for WithDeadline<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        wait_ready(self.stream.as_fd(), libc::POLLIN, self.deadline)?;
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write + AsFd + ?Sized> Write for WithDeadline<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        wait_ready(self.stream.as_fd(), libc::POLLOUT, self.deadline)?;
+        self.stream.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        wait_ready(self.stream.as_fd(), libc::POLLOUT, self.deadline)?;
+        self.stream.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
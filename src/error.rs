@@ -0,0 +1,97 @@
+use crate::peer_cred::PeerCredentials;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+pub type Result<T> = std::result::Result<T, IpcError>;
+
+#[derive(Debug)]
+pub enum IpcError {
+    /// Nothing arrived before the deadline
+    Timeout,
+    /// The other side of the channel has exited or closed it
+    PeerGone,
+    /// The peer's credentials aren't allowed by the transport's peer policy
+    PeerRejected(PeerCredentials),
+    /// The peer sent something the transport doesn't understand
+    Protocol(String),
+    /// A shared memory segment or mapped file wasn't laid out the way we expect
+    IncompatibleSegment(String),
+    /// A message didn't fit, or didn't match the size the channel expects
+    SizeMismatch { expected: usize, actual: usize },
+    /// A syscall failed
+    Os(io::Error),
+    /// Failures reported by the shared_memory and iceoryx2 crates
+    Backend(String),
+}
+
+impl IpcError {
+    pub fn protocol(msg: impl Into<String>) -> Self {
+        IpcError::Protocol(msg.into())
+    }
+
+    pub fn incompatible_segment(msg: impl Into<String>) -> Self {
+        IpcError::IncompatibleSegment(msg.into())
+    }
+
+    // The libraries we sit on mostly return their own error types, or boxed errors which aren't
+    // Send, so we keep their message only
+    pub fn backend(err: impl Display) -> Self {
+        IpcError::Backend(err.to_string())
+    }
+}
+
+impl Display for IpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpcError::Timeout => write!(f, "Timed out waiting for the peer"),
+            IpcError::PeerGone => write!(f, "The peer has gone away"),
+            IpcError::PeerRejected(peer) => {
+                write!(
+                    f,
+                    "Rejected peer {}, which the peer policy doesn't allow",
+                    peer
+                )
+            }
+            IpcError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            IpcError::IncompatibleSegment(msg) => write!(f, "Incompatible segment: {}", msg),
+            IpcError::SizeMismatch { expected, actual } => write!(
+                f,
+                "Message size mismatch: expected {} bytes, got {}",
+                expected, actual
+            ),
+            IpcError::Os(e) => write!(f, "OS error: {}", e),
+            IpcError::Backend(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for IpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IpcError::Os(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for IpcError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // Sockets with a read timeout report WouldBlock when it expires
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => IpcError::Timeout,
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected => IpcError::PeerGone,
+            _ => IpcError::Os(e),
+        }
+    }
+}
+
+impl From<shared_memory::ShmemError> for IpcError {
+    fn from(e: shared_memory::ShmemError) -> Self {
+        IpcError::backend(e)
+    }
+}
@@ -0,0 +1,272 @@
+// Named pipes, so processes which don't share stdin/stdout can talk over pipes by path. The
+// producer makes a directory for the run holding two FIFOs, `requests` and `responses`, and
+// messages are framed as in the framing module.
+//
+// Opening one end of a FIFO blocks until the other end is opened too, so two sides opening their
+// pair in different orders would wait on each other forever. Both sides open `requests` first and
+// `responses` second, and neither blocks in open: ends are opened non-blocking, and a writer with
+// no reader yet gets ENXIO and tries again. The producer holds the read end of `responses` from
+// the start, so once the consumer has opened that for writing the producer has opened `requests`,
+// and neither side can read an EOF from a pipe whose writer hasn't arrived yet.
+
+use crate::deadline::{self, WithDeadline};
+use crate::error::{IpcError, Result};
+use crate::framing::{read_frame, write_frame};
+use crate::transport::{
+    endpoint_path, wait_for_consumer, NamedEndpoint, Runner, Transport, TransportConfig,
+    READY_POLL_INTERVAL,
+};
+use std::ffi::CString;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+
+const READY: u8 = 1;
+// Only the owner can get at the FIFOs
+const DIR_MODE: u32 = 0o700;
+const FIFO_MODE: libc::mode_t = 0o600;
+
+const REQUESTS: &str = "requests";
+const RESPONSES: &str = "responses";
+
+fn fifo_dir(name: &str) -> PathBuf {
+    endpoint_path(name, ".fifo")
+}
+
+fn make_fifo(path: &Path) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| IpcError::protocol(format!("FIFO path {:?} contains a nul", path)))?;
+    if unsafe { libc::mkfifo(path.as_ptr(), FIFO_MODE) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Remove the run's FIFOs and their directory. Anything else in the directory is left alone, and
+// then so is the directory
+fn remove_fifos(dir: &Path) {
+    for fifo in [REQUESTS, RESPONSES] {
+        let path = dir.join(fifo);
+        if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_fifo()) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    let _ = std::fs::remove_dir(dir);
+}
+
+// Open the read end of a FIFO, which doesn't wait for a writer when non-blocking
+fn open_read(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?)
+}
+
+// Open the write end of a FIFO without blocking, None if it has no reader yet
+fn try_open_write(path: &Path) -> Result<Option<File>> {
+    let opened = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path);
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Reads and writes block from here on, timed by polling as with the other pipes
+fn set_blocking(file: &File) -> Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// The run's directory, whose FIFOs are removed when this is dropped
+#[derive(Debug)]
+pub struct FifoDir(PathBuf);
+
+impl FifoDir {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for FifoDir {
+    fn drop(&mut self) {
+        remove_fifos(&self.0);
+    }
+}
+
+/// The producer's side before the consumer has opened the FIFOs. Dropping it removes them
+pub struct FifoListener {
+    name: String,
+    dir: FifoDir,
+    // The read end of `responses`, held from the start so the consumer can always open its end
+    responses: File,
+}
+
+impl FifoListener {
+    /// Make the run's directory and FIFOs, replacing any a crashed run under the same name left
+    /// behind
+    pub fn new(name: &str) -> Result<Self> {
+        let path = fifo_dir(name);
+        remove_fifos(&path);
+        DirBuilder::new().mode(DIR_MODE).create(&path)?;
+        let dir = FifoDir(path);
+        make_fifo(&dir.path().join(REQUESTS))?;
+        make_fifo(&dir.path().join(RESPONSES))?;
+        let responses = open_read(&dir.path().join(RESPONSES))?;
+        Ok(Self {
+            name: name.to_string(),
+            dir,
+            responses,
+        })
+    }
+}
+
+pub struct FifoWrapper {
+    pub input: File,
+    pub output: File,
+    // Set on the producer's side, which removes the FIFOs once done
+    pub dir: Option<FifoDir>,
+    buf: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+impl FifoWrapper {
+    fn new(input: File, output: File, dir: Option<FifoDir>, max_message_size: usize) -> Self {
+        Self {
+            input,
+            output,
+            dir,
+            buf: vec![0; max_message_size],
+            timeout: None,
+        }
+    }
+}
+
+impl Transport for FifoWrapper {
+    const CONSUMER: &'static str = "fifo";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = FifoListener;
+
+    type Endpoint = NamedEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "Named pipes".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        FifoListener::new(&config.resolve_endpoint_name())
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(NamedEndpoint {
+            name: listener.name.clone(),
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let FifoListener {
+            dir, mut responses, ..
+        } = listener;
+        // Open `requests` once the consumer has, then wait for the ready byte it sends on
+        // `responses` after opening that. Until it has, reading `responses` finds no writer and
+        // returns 0
+        let mut requests = None;
+        wait_for_consumer(child, config.ready_timeout, || {
+            if requests.is_none() {
+                requests = try_open_write(&dir.path().join(REQUESTS))?;
+                if requests.is_none() {
+                    return Ok(false);
+                }
+            }
+            let mut ready = [0; 1];
+            match responses.read(&mut ready) {
+                Ok(0) => Ok(false),
+                Ok(_) if ready[0] == READY => Ok(true),
+                Ok(_) => Err(IpcError::protocol("Expected the consumer's ready byte")),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                Err(e) => Err(e.into()),
+            }
+        })?;
+        let requests = requests.unwrap();
+        set_blocking(&requests)?;
+        set_blocking(&responses)?;
+        Ok(FifoWrapper::new(
+            requests,
+            responses,
+            Some(dir),
+            config.max_message_size,
+        ))
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let dir = fifo_dir(&endpoint.name);
+        let requests = open_read(&dir.join(REQUESTS))?;
+        // The producer has held the read end since it made the FIFOs, so this only waits while
+        // it's starting up
+        let deadline = deadline::deadline(Some(config.ready_timeout));
+        let mut responses = loop {
+            if let Some(responses) = try_open_write(&dir.join(RESPONSES))? {
+                break responses;
+            }
+            deadline::check(deadline)?;
+            sleep(READY_POLL_INTERVAL);
+        };
+        set_blocking(&requests)?;
+        set_blocking(&responses)?;
+        // Sent bare rather than framed, the producer reads it before any framing starts
+        responses.write_all(&[READY])?;
+        Ok(FifoWrapper::new(
+            responses,
+            requests,
+            None,
+            config.max_message_size,
+        ))
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let mut input = WithDeadline::new(&mut self.input, timeout);
+        write_frame(&mut input, data, self.buf.len())?;
+        input.flush()?;
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let mut output = WithDeadline::new(&mut self.output, timeout);
+        let len = read_frame(&mut output, &mut self.buf)?;
+        Ok(&self.buf[..len])
+    }
+
+    fn close(&mut self) {
+        // Dropping the directory removes the FIFOs
+        self.dir = None;
+    }
+}
+
+pub type FifoRunner = Runner<FifoWrapper>;
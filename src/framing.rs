@@ -0,0 +1,187 @@
+// Length-prefixed messages over byte streams. Each frame is a little-endian `u32` length
+// followed by that many bytes of payload.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use std::io::{ErrorKind, IoSlice, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const HEADER_LEN: usize = std::mem::size_of::<u32>();
+
+/// Write `data` as a single frame. Fails with [`IpcError::SizeMismatch`] if it's bigger than
+/// `max_message_size`, without writing anything
+pub fn write_frame<W: Write + ?Sized>(
+    writer: &mut W,
+    data: &[u8],
+    max_message_size: usize,
+) -> Result<()> {
+    if data.len() > max_message_size || data.len() > u32::MAX as usize {
+        return Err(IpcError::SizeMismatch {
+            expected: max_message_size,
+            actual: data.len(),
+        });
+    }
+    let header = (data.len() as u32).to_le_bytes();
+
+    // Header and payload go out together, so nodelay sockets don't send a tiny segment first
+    let mut slices = [IoSlice::new(&header), IoSlice::new(data)];
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(IpcError::PeerGone),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Read one frame into `buf` and return its length. A frame longer than `buf` fails with
+/// [`IpcError::SizeMismatch`], after which the stream is out of step and should be dropped.
+///
+/// Only a timeout before the frame's first byte is [`IpcError::Timeout`], which leaves the stream
+/// in step for another try. Running out of time partway through is a protocol error
+pub fn read_frame<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut header = [0; HEADER_LEN];
+    let first = loop {
+        match reader.read(&mut header) {
+            Ok(0) => return Err(IpcError::PeerGone),
+            Ok(n) => break n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    read_rest(reader, &mut header[first..])?;
+    let len = u32::from_le_bytes(header) as usize;
+    if len > buf.len() {
+        return Err(IpcError::SizeMismatch {
+            expected: buf.len(),
+            actual: len,
+        });
+    }
+    read_rest(reader, &mut buf[..len])?;
+    Ok(len)
+}
+
+// Read the rest of a frame we've started on
+fn read_rest<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| deadline::partway(IpcError::from(e)))
+}
+
+/// Async version of [`write_frame`]
+pub async fn write_frame_async<W: AsyncWrite + Unpin + ?Sized>(
+    writer: &mut W,
+    data: &[u8],
+    max_message_size: usize,
+) -> Result<()> {
+    if data.len() > max_message_size || data.len() > u32::MAX as usize {
+        return Err(IpcError::SizeMismatch {
+            expected: max_message_size,
+            actual: data.len(),
+        });
+    }
+    let header = (data.len() as u32).to_le_bytes();
+
+    let mut slices = [IoSlice::new(&header), IoSlice::new(data)];
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        match writer.write_vectored(slices).await {
+            Ok(0) => return Err(IpcError::PeerGone),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// How far [`read_frame_async`] has got through the frame it's reading, kept by the caller so a
+/// read which is cancelled, say by a timeout, carries on where it left off next time
+#[derive(Debug, Default)]
+pub struct FrameProgress {
+    header: [u8; HEADER_LEN],
+    read: usize,
+}
+
+/// Async version of [`read_frame`]. Cancel safe, as long as it's called again with the same
+/// `progress` and `buf`, which hold what has been read so far
+pub async fn read_frame_async<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+    buf: &mut [u8],
+    progress: &mut FrameProgress,
+) -> Result<usize> {
+    while progress.read < HEADER_LEN {
+        progress.read += read_some(reader, &mut progress.header[progress.read..]).await?;
+    }
+    let len = u32::from_le_bytes(progress.header) as usize;
+    if len > buf.len() {
+        return Err(IpcError::SizeMismatch {
+            expected: buf.len(),
+            actual: len,
+        });
+    }
+    while progress.read < HEADER_LEN + len {
+        let start = progress.read - HEADER_LEN;
+        progress.read += read_some(reader, &mut buf[start..len]).await?;
+    }
+    progress.read = 0;
+    Ok(len)
+}
+
+// A single read, which unlike read_exact loses nothing if it's cancelled
+async fn read_some<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    loop {
+        match reader.read(buf).await {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn read_cancelled_partway_through_a_frame_carries_on() -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        runtime.block_on(async {
+            let (mut writer, mut reader) = tokio::io::duplex(64);
+            let mut frame = Vec::new();
+            write_frame(&mut frame, b"split across reads", 64)?;
+            let mut buf = [0; 64];
+            let mut progress = FrameProgress::default();
+
+            // Cut off partway through the header, then partway through the payload
+            let parts = [&frame[..2], &frame[2..10], &frame[10..]];
+            for (i, part) in parts.iter().enumerate() {
+                writer.write_all(part).await?;
+                let read = tokio::time::timeout(
+                    Duration::from_millis(10),
+                    read_frame_async(&mut reader, &mut buf, &mut progress),
+                )
+                .await;
+                if i + 1 < parts.len() {
+                    assert!(read.is_err(), "Read a frame from {:?}", part);
+                    continue;
+                }
+                let len = read.expect("Timed out with the whole frame sent")?;
+                assert_eq!(&buf[..len], b"split across reads");
+            }
+
+            // And the next frame starts afresh
+            write_frame_async(&mut writer, b"next", 64).await?;
+            let len = read_frame_async(&mut reader, &mut buf, &mut progress).await?;
+            assert_eq!(&buf[..len], b"next");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,169 @@
+// An event in shared memory which a waiter can spin on, sleep on, or spin on for a while and then
+// sleep on. The event is one futex word:
+//
+// CLEAR -> SIGNALLED when the other side signals it, and back to CLEAR when the waiter takes it.
+// A waiter about to sleep moves it from CLEAR to SLEEPING first, so the signaller knows to wake
+// it. A signaller which sees CLEAR knows nobody is asleep and skips the syscall, so spinning costs
+// no more than a plain flag would.
+
+use std::fmt::{Display, Formatter};
+use std::hint::spin_loop;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const CLEAR: u32 = 0;
+const SIGNALLED: u32 = 1;
+// Clear, with the waiter asleep or about to be
+const SLEEPING: u32 = 2;
+
+// Spinners only read the clock this often, to keep the check off the fast path
+const SPINS_PER_CLOCK_READ: u32 = 1024;
+
+/// Spins before sleeping with `adaptive` when no count is given
+pub const DEFAULT_ADAPTIVE_SPINS: u32 = 10_000;
+
+/// How the shmem and mmap transports wait for the other side's event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Spin until signalled, keeping a core busy. Lowest latency
+    #[default]
+    Spin,
+    /// Spin this many times, then sleep until woken
+    Adaptive(u32),
+    /// Sleep until woken straight away, so an idle side uses no CPU
+    Block,
+}
+
+impl Display for WaitStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitStrategy::Spin => write!(f, "spin"),
+            WaitStrategy::Adaptive(spins) => write!(f, "adaptive:{}", spins),
+            WaitStrategy::Block => write!(f, "block"),
+        }
+    }
+}
+
+impl FromStr for WaitStrategy {
+    type Err = String;
+
+    /// `spin`, `block`, or `adaptive` with an optional spin count, e.g. `adaptive:1000`
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "spin" => Ok(WaitStrategy::Spin),
+            None if s == "block" => Ok(WaitStrategy::Block),
+            None if s == "adaptive" => Ok(WaitStrategy::Adaptive(DEFAULT_ADAPTIVE_SPINS)),
+            Some(("adaptive", spins)) => spins
+                .parse()
+                .map(WaitStrategy::Adaptive)
+                .map_err(|_| format!("spin count {:?} isn't a whole number", spins)),
+            _ => Err(format!(
+                "expected spin, block or adaptive:<spins>, not {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// One side's event, living in shared memory
+#[repr(transparent)]
+pub(crate) struct FutexEvent(AtomicU32);
+
+impl FutexEvent {
+    /// Put a new segment's event in its starting state
+    pub(crate) fn init(&self) {
+        self.0.store(CLEAR, Ordering::Relaxed);
+    }
+
+    /// Signal the event, waking the waiter if it has gone to sleep
+    pub(crate) fn set(&self) {
+        if self.0.swap(SIGNALLED, Ordering::Release) == SLEEPING {
+            futex_wake(&self.0);
+        }
+    }
+
+    /// Withdraw a signal the waiter hasn't taken yet
+    pub(crate) fn reset(&self) {
+        let _ = self
+            .0
+            .compare_exchange(SIGNALLED, CLEAR, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Take the signal if the event has one, clearing it
+    pub(crate) fn try_take(&self) -> bool {
+        self.0
+            .compare_exchange(SIGNALLED, CLEAR, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Wait up to `timeout` for a signal and take it, returning whether there was one
+    pub(crate) fn wait(&self, strategy: WaitStrategy, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let spins = match strategy {
+            WaitStrategy::Spin => None,
+            WaitStrategy::Adaptive(spins) => Some(spins),
+            WaitStrategy::Block => Some(0),
+        };
+        let mut spun = 0u32;
+        loop {
+            if self.try_take() {
+                return true;
+            }
+            if spins.is_some_and(|spins| spun >= spins) {
+                break;
+            }
+            spun = spun.wrapping_add(1);
+            if spun.is_multiple_of(SPINS_PER_CLOCK_READ) && Instant::now() >= deadline {
+                return false;
+            }
+            spin_loop();
+        }
+        loop {
+            // Tell the signaller we're going to sleep, unless it has signalled in the meantime
+            if let Err(SIGNALLED) =
+                self.0
+                    .compare_exchange(CLEAR, SLEEPING, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                if self.try_take() {
+                    return true;
+                }
+                continue;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return self.try_take();
+            }
+            futex_wait(&self.0, SLEEPING, left);
+            if self.try_take() {
+                return true;
+            }
+        }
+    }
+}
+
+// Sleep while `word` holds `expected`, for up to `timeout`. Wakeups can be spurious, so callers
+// check the word again afterwards
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    // Not FUTEX_PRIVATE_FLAG, the word is shared with another process
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            &timeout as *const libc::timespec,
+        );
+    }
+}
+
+// Each event has a single waiter, the other side
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, 1);
+    }
+}
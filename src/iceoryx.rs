@@ -1,134 +1,156 @@
-use crate::{get_payload, ExecutionResult, KB};
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::transport::{wait_for_consumer, NamedEndpoint, Runner, Transport, TransportConfig};
 use iceoryx2::port::publisher::Publisher;
 use iceoryx2::port::subscriber::Subscriber;
 use iceoryx2::prelude::*;
-use std::process::{Child, Command};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use iceoryx2::sample::Sample;
+use std::process::Child;
+use std::time::Duration;
+
+const READY: u8 = 1;
 
 pub struct IceoryxWrapper {
+    pub name: String,
     pub publisher: Publisher<ipc::Service, [u8], ()>,
     pub subscriber: Subscriber<ipc::Service, [u8], ()>,
+    received: Option<Sample<ipc::Service, [u8], ()>>,
+    timeout: Option<Duration>,
 }
 
 impl IceoryxWrapper {
-    pub fn new(is_producer: bool, data_size: usize) -> IceoryxWrapper {
-        let node = NodeBuilder::new().create::<ipc::Service>().unwrap();
-        let request_name = ServiceName::new(&format!("Request")).unwrap();
+    /// The request and response services are created under `name`
+    pub fn new(name: &str, is_producer: bool, max_message_size: usize) -> Result<IceoryxWrapper> {
+        let node = NodeBuilder::new()
+            .create::<ipc::Service>()
+            .map_err(IpcError::backend)?;
+        let request_name =
+            ServiceName::new(&format!("{}/Request", name)).map_err(IpcError::backend)?;
         let request_service = node
             .service_builder(&request_name)
             .publish_subscribe::<[u8]>()
             .open_or_create()
-            .unwrap();
+            .map_err(IpcError::backend)?;
 
-        let response_name = ServiceName::new(&format!("Respose")).unwrap();
+        let response_name =
+            ServiceName::new(&format!("{}/Response", name)).map_err(IpcError::backend)?;
         let response_service = node
             .service_builder(&response_name)
             .publish_subscribe::<[u8]>()
             .open_or_create()
-            .unwrap();
+            .map_err(IpcError::backend)?;
 
         let (publisher, subscriber) = if is_producer {
             (
                 request_service
                     .publisher_builder()
-                    .max_slice_len(data_size)
+                    .max_slice_len(max_message_size)
+                    .create()
+                    .map_err(IpcError::backend)?,
+                response_service
+                    .subscriber_builder()
                     .create()
-                    .unwrap(),
-                response_service.subscriber_builder().create().unwrap(),
+                    .map_err(IpcError::backend)?,
             )
         } else {
             (
                 response_service
                     .publisher_builder()
-                    .max_slice_len(data_size)
+                    .max_slice_len(max_message_size)
                     .create()
-                    .unwrap(),
-                request_service.subscriber_builder().create().unwrap(),
+                    .map_err(IpcError::backend)?,
+                request_service
+                    .subscriber_builder()
+                    .create()
+                    .map_err(IpcError::backend)?,
             )
         };
 
-        IceoryxWrapper {
+        Ok(IceoryxWrapper {
+            name: name.to_string(),
             publisher,
             subscriber,
-        }
+            received: None,
+            timeout: None,
+        })
     }
 }
 
-pub struct IceoryxRunner {
-    child_proc: Option<Child>,
-    wrapper: IceoryxWrapper,
-    request_data: Vec<u8>,
-    response_data: Vec<u8>,
-    data_size: usize,
-}
+impl Transport for IceoryxWrapper {
+    const CONSUMER: &'static str = "iceoryx";
 
-impl IceoryxRunner {
-    pub fn new(start_child: bool, data_size: usize) -> IceoryxRunner {
-        let wrapper = IceoryxWrapper::new(true, data_size);
-        let exe = crate::executable_path("iceoryx_consumer");
-
-        let child_proc = if start_child {
-            // None
-            Some(
-                Command::new(exe)
-                    .args(&[data_size.to_string()])
-                    .spawn()
-                    .unwrap(),
-            )
-        } else {
-            None
-        };
-        // Awkward sleep again to wait for consumer to be ready
-        sleep(Duration::from_secs(2));
+    const VARIABLE_LENGTH: bool = true;
 
-        let (request_data, response_data) = get_payload(data_size);
+    type Listener = IceoryxWrapper;
 
-        Self {
-            child_proc,
-            wrapper,
-            request_data,
-            response_data,
-            data_size,
-        }
+    type Endpoint = NamedEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "Iceoryx".to_string()
     }
 
-    pub fn run(&mut self, n: usize, print: bool) {
-        let start = Instant::now();
-        for _ in 0..n {
-            let sample = self
-                .wrapper
-                .publisher
-                .loan_slice_uninit(self.data_size)
-                .unwrap();
-            let sample = sample.write_from_slice(self.request_data.as_slice());
-            sample.send().unwrap();
-
-            // Waiting for response
-            loop {
-                if let Some(recv_payload) = self.wrapper.subscriber.receive().unwrap() {
-                    #[cfg(debug_assertions)]
-                    if recv_payload.ne(&self.response_data) {
-                        panic!("Sent request didn't get response")
-                    }
-
-                    break;
-                }
-            }
-        }
-        if print {
-            let elapsed = start.elapsed();
-            let res =
-                ExecutionResult::new(format!("Iceoryx - {}KB", self.data_size / KB), elapsed, n);
-            res.print_info();
-        }
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        IceoryxWrapper::new(
+            &config.resolve_endpoint_name(),
+            true,
+            config.max_message_size,
+        )
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<NamedEndpoint> {
+        Ok(NamedEndpoint {
+            name: listener.name.clone(),
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer publishes a ready sample once its ports are up
+        wait_for_consumer(child, config.ready_timeout, || {
+            let ready = listener.subscriber.receive().map_err(IpcError::backend)?;
+            Ok(ready.is_some())
+        })?;
+        Ok(listener)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &NamedEndpoint) -> Result<Self> {
+        let mut wrapper = IceoryxWrapper::new(&endpoint.name, false, config.max_message_size)?;
+        wrapper.send(&[READY])?;
+        Ok(wrapper)
     }
-}
 
-impl Drop for IceoryxRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut c) = self.child_proc {
-            c.kill().unwrap();
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // Publishing never waits on the subscriber
+    fn send_timeout(&mut self, data: &[u8], _timeout: Option<Duration>) -> Result<()> {
+        let sample = self
+            .publisher
+            .loan_slice_uninit(data.len())
+            .map_err(IpcError::backend)?;
+        let sample = sample.write_from_slice(data);
+        sample.send().map_err(IpcError::backend)?;
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        // Hand the previous sample back before waiting on the next one
+        self.received = None;
+        while self.received.is_none() {
+            deadline::check(deadline)?;
+            self.received = self.subscriber.receive().map_err(IpcError::backend)?;
         }
+        Ok(self.received.as_deref().unwrap())
     }
 }
+
+pub type IceoryxRunner = Runner<IceoryxWrapper>;
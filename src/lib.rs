@@ -1,21 +1,46 @@
 use std::path::PathBuf;
+use std::process::Command;
 use std::time::Duration;
 
+pub mod async_transport;
+mod deadline;
+pub mod error;
+pub mod fifo;
+pub mod framing;
+pub mod futex;
 pub mod iceoryx;
+pub mod liveness;
 pub mod mmap;
+pub mod mqueue;
+pub mod peer_cred;
 pub mod pipes;
+pub mod reliable;
+pub mod segment;
+pub mod serve;
 pub mod shmem;
+pub mod shmem_broadcast;
+pub mod shmem_eventfd;
+pub mod shmem_queue;
+pub mod shmem_ring;
+pub mod sysv_msg;
+pub mod sysv_shm;
 pub mod tcp;
+pub mod transport;
 pub mod udp;
 pub mod unix_datagram;
 pub mod unix_stream;
 
+pub use error::{IpcError, Result};
+
 use rand::rngs::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
 
 pub const KB: usize = 1024;
 
+/// Subcommand of the ipc binary which runs a consumer
+pub const SERVE: &str = "serve";
+
 pub fn generate_random_data(data_size: usize, seed: u64) -> Vec<u8> {
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                             abcdefghijklmnopqrstuvwxyz\
@@ -36,6 +61,12 @@ pub fn get_payload(data_size: usize) -> (Vec<u8>, Vec<u8>) {
     (request_data, response_data)
 }
 
+// Deterministic spread of message sizes between 1 and `max_size` for mixed size runs
+pub fn get_mixed_sizes(max_size: usize, count: usize) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(3);
+    (0..count).map(|_| rng.gen_range(1..=max_size)).collect()
+}
+
 pub fn cpu_warmup() {
     let warmup = std::time::Instant::now();
     loop {
@@ -45,10 +76,25 @@ pub fn cpu_warmup() {
     }
 }
 
+/// CPU time this process has used so far, in user and kernel mode
+pub fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+    Duration::from_micros(micros(usage.ru_utime) + micros(usage.ru_stime))
+}
+
+/// How busy `cpu` of CPU time over `elapsed` kept a core, as a percentage
+pub fn core_usage(cpu: Duration, elapsed: Duration) -> f64 {
+    100.0 * cpu.as_secs_f64() / elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
 pub struct ExecutionResult {
     name: String,
     elapsed: Duration,
     cycles: usize,
+    // CPU time the producer used over the run, when it was measured
+    cpu: Option<Duration>,
 }
 
 impl ExecutionResult {
@@ -57,9 +103,15 @@ impl ExecutionResult {
             name,
             elapsed,
             cycles,
+            cpu: None,
         }
     }
 
+    fn cpu(mut self, cpu: Duration) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+
     fn print_info(&self) {
         let duration = humantime::Duration::from(self.elapsed);
         let ps = 1_000_000f32 * (self.cycles as f32) / (duration.as_micros() as f32);
@@ -69,9 +121,28 @@ impl ExecutionResult {
             "IPC method - {}\n\t{} cycles completed in {} \n\t{} per second\n\t{} per operation",
             self.name, self.cycles, duration, ps, per_op
         );
+        if let Some(cpu) = self.cpu {
+            println!(
+                "\tProducer used {} of CPU, {:.0}% of a core",
+                humantime::Duration::from(cpu),
+                core_usage(cpu, self.elapsed)
+            );
+        }
     }
 }
 
+// Consumers are the ipc binary run as `ipc serve <method>`. When that's us we spawn our own
+// executable, so the consumer is always the same build as the producer
+fn consumer_command(method: &str) -> Command {
+    let exe = std::env::current_exe()
+        .ok()
+        .filter(|exe| exe.file_stem().is_some_and(|stem| stem == "ipc"))
+        .unwrap_or_else(|| executable_path("ipc"));
+    let mut command = Command::new(exe);
+    command.args([SERVE, method]);
+    command
+}
+
 fn executable_path(name: &str) -> PathBuf {
     #[cfg(target_os = "windows")]
     let exe = name.to_owned() + ".exe";
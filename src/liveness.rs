@@ -0,0 +1,83 @@
+// Shared memory transports have no connection to break when the other side dies, so a wait for
+// its signal would spin forever. Each side records its pid in the segment and the other side
+// watches that process while it waits.
+
+use crate::error::{IpcError, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+/// Longest a wait on a dead peer goes on before failing with [`IpcError::PeerGone`]
+pub const LIVENESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// Spin loops only read the clock this often, to keep the check off the fast path
+const SPINS_PER_CLOCK_READ: u32 = 1024;
+
+pub(crate) struct PeerWatch {
+    pid: u32,
+    // Becomes readable once the process exits, even before its parent reaps it. A plain pid
+    // check would take an unreaped child for alive
+    pidfd: Option<OwnedFd>,
+    spins: u32,
+    last_check: Instant,
+}
+
+impl PeerWatch {
+    pub(crate) fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            pidfd: open_pidfd(pid),
+            spins: 0,
+            last_check: Instant::now(),
+        }
+    }
+
+    pub(crate) fn is_alive(&self) -> bool {
+        match &self.pidfd {
+            Some(pidfd) => {
+                let mut poll_fd = libc::pollfd {
+                    fd: pidfd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                unsafe { libc::poll(&mut poll_fd, 1, 0) == 0 }
+            }
+            None => {
+                let res = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+                res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+            }
+        }
+    }
+
+    /// Fails with [`IpcError::PeerGone`] if the peer has exited
+    pub(crate) fn ensure_alive(&self) -> Result<()> {
+        if self.is_alive() {
+            Ok(())
+        } else {
+            Err(IpcError::PeerGone)
+        }
+    }
+
+    /// For calling on every turn of a spin loop, only checks once per [`LIVENESS_INTERVAL`]
+    pub(crate) fn check(&mut self) -> Result<()> {
+        self.spins = self.spins.wrapping_add(1);
+        if !self.spins.is_multiple_of(SPINS_PER_CLOCK_READ)
+            || self.last_check.elapsed() < LIVENESS_INTERVAL
+        {
+            return Ok(());
+        }
+        self.last_check = Instant::now();
+        self.ensure_alive()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_pidfd(pid: u32) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    // Older kernels don't have pidfds, we fall back to signalling the pid
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_pidfd(_pid: u32) -> Option<OwnedFd> {
+    None
+}
@@ -1,106 +1,181 @@
 use clap::Parser;
-use ipc::iceoryx::IceoryxRunner;
-use ipc::mmap::MmapRunner;
-use ipc::pipes::PipeRunner;
-use ipc::shmem::ShmemRunner;
-use ipc::tcp::TcpRunner;
-use ipc::udp::UdpRunner;
-use ipc::unix_datagram::UnixDatagramRunner;
-use ipc::unix_stream::UnixStreamRunner;
-use ipc::{cpu_warmup, KB};
-
-fn main() {
-    let args = Cli::parse();
-    match args.method {
-        Method::Stdout => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut pr = PipeRunner::new(data_size);
+use ipc::async_transport::{AsyncRunner, AsyncTransport};
+use ipc::fifo::FifoWrapper;
+use ipc::futex::WaitStrategy;
+use ipc::iceoryx::IceoryxWrapper;
+use ipc::mmap::MmapWrapper;
+use ipc::mqueue::MqueueWrapper;
+use ipc::pipes::{AsyncPipeWrapper, PipeWrapper};
+use ipc::shmem::ShmemWrapper;
+use ipc::shmem_broadcast::{
+    run_fan_out, run_subscriber, SlowSubscriberPolicy, SubscriberArgs, SUBSCRIBER,
+};
+use ipc::shmem_eventfd::ShmemEventFdWrapper;
+use ipc::shmem_queue::{run_scaling, run_worker, WorkerArgs, WORKER};
+use ipc::shmem_ring::ShmemRingWrapper;
+use ipc::sysv_msg::SysvMsgWrapper;
+use ipc::sysv_shm::SysvShmWrapper;
+use ipc::tcp::{AsyncTcpStreamWrapper, TcpStreamWrapper};
+use ipc::transport::{
+    run_consumer, Endpoint, Runner, Transport, TransportConfig, DEFAULT_RING_SLOTS,
+};
+use ipc::udp::{AsyncUdpStreamWrapper, UdpStreamWrapper};
+use ipc::unix_datagram::{AsyncUnixDatagramWrapper, UnixDatagramWrapper};
+use ipc::unix_stream::{AsyncUnixStreamWrapper, UnixStreamWrapper};
+use ipc::{cpu_warmup, IpcError, KB, SERVE};
 
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+fn main() -> Result<(), IpcError> {
+    // Consumers are this binary run as `ipc serve <method>`, which is kept out of the help
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == SERVE) {
+        return serve(Serve::parse_from(std::env::args_os().skip(1)).method);
+    }
 
-                pr.run(args.number, true);
-            }
+    let args = Cli::parse();
+    match args.method {
+        Method::Stdout => run_with_async::<PipeWrapper, AsyncPipeWrapper>(&args),
+        Method::Fifo => run::<FifoWrapper>(&args),
+        Method::Shmem => run::<ShmemWrapper>(&args),
+        Method::Shmemevent => run::<ShmemEventFdWrapper>(&args),
+        Method::Shmemring => run::<ShmemRingWrapper>(&args),
+        Method::Shmemqueue => run_queue(&args),
+        Method::Sysvmsg => run::<SysvMsgWrapper>(&args),
+        Method::Sysvshm => run::<SysvShmWrapper>(&args),
+        Method::Broadcast => run_broadcast(&args),
+        Method::Tcp => run_with_async::<TcpStreamWrapper, AsyncTcpStreamWrapper>(&args),
+        Method::Udp => run_with_async::<UdpStreamWrapper, AsyncUdpStreamWrapper>(&args),
+        Method::Iceoryx => run::<IceoryxWrapper>(&args),
+        Method::Mmap => run::<MmapWrapper>(&args),
+        Method::Mqueue => run::<MqueueWrapper>(&args),
+        Method::Unixstream => run_with_async::<UnixStreamWrapper, AsyncUnixStreamWrapper>(&args),
+        Method::Unixdatagram => {
+            run_with_async::<UnixDatagramWrapper, AsyncUnixDatagramWrapper>(&args)
         }
-        Method::Shmem => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = ShmemRunner::new(args.start_child, data_size);
+    }
+}
 
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+fn run<T: Transport>(args: &Cli) -> Result<(), IpcError> {
+    for data_size in 1..=args.kb_max {
+        let data_size = 2u64.pow(data_size as u32) as usize * KB;
+        run_blocking::<T>(args, data_size)?;
+    }
+    Ok(())
+}
 
-                runner.run(args.number, true);
-            }
-        }
-        Method::Tcp => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = TcpRunner::new(args.start_child, true, data_size);
+// Each size is run with the blocking transport and then, if asked for, its async version
+fn run_with_async<T, A>(args: &Cli) -> Result<(), IpcError>
+where
+    T: Transport,
+    A: AsyncTransport<Blocking = T>,
+{
+    for data_size in 1..=args.kb_max {
+        let data_size = 2u64.pow(data_size as u32) as usize * KB;
+        run_blocking::<T>(args, data_size)?;
+        if args.with_async {
+            let mut runner = AsyncRunner::<A>::new(args.start_child, config(args, data_size))?;
 
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+            core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+            cpu_warmup();
 
-                runner.run(args.number, true);
-            }
+            runner.run(args.number, true)?;
         }
-        Method::Udp => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = UdpRunner::new(true, data_size);
-
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+    }
+    Ok(())
+}
 
-                runner.run(args.number, true);
-                drop(runner);
-            }
-        }
-        Method::Iceoryx => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = IceoryxRunner::new(true, data_size);
+fn run_blocking<T: Transport>(args: &Cli, data_size: usize) -> Result<(), IpcError> {
+    let mut runner = Runner::<T>::new(args.start_child, config(args, data_size))?;
 
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+    core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
+    cpu_warmup();
 
-                runner.run(args.number, true);
-            }
-        }
-        Method::Mmap => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = MmapRunner::new(true, data_size);
+    runner.run(args.number, true)
+}
 
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+fn config(args: &Cli, data_size: usize) -> TransportConfig {
+    let config = TransportConfig::new(data_size)
+        .mixed_sizes(args.mixed_sizes)
+        .streaming(args.streaming)
+        .ring_slots(args.ring_slots)
+        .wait_strategy(args.wait_strategy)
+        .ready_timeout(args.ready_timeout.into())
+        .shutdown_timeout(args.shutdown_timeout.into())
+        .io_timeout(args.io_timeout.map(Into::into));
+    match &args.endpoint_name {
+        Some(name) => config.endpoint_name(name),
+        None => config,
+    }
+}
 
-                runner.run(args.number, true);
-            }
+// Not a ping-pong between two processes, so it gets its own loop. Runs with 1 to `workers`
+// producers, each paired with as many consumers
+fn run_queue(args: &Cli) -> Result<(), IpcError> {
+    for data_size in 1..=args.kb_max {
+        let data_size = 2u64.pow(data_size as u32) as usize * KB;
+        for workers in 1..=args.workers {
+            run_scaling(
+                workers,
+                workers,
+                args.number,
+                data_size,
+                args.queue_capacity,
+                args.ready_timeout.into(),
+                true,
+            )?;
         }
-        Method::Unixstream => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = UnixStreamRunner::new(true, data_size);
-
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+    }
+    Ok(())
+}
 
-                runner.run(args.number, true);
-            }
+// One publisher feeding 1 to `workers` subscribers
+fn run_broadcast(args: &Cli) -> Result<(), IpcError> {
+    let policy = if args.backpressure {
+        SlowSubscriberPolicy::Backpressure
+    } else {
+        SlowSubscriberPolicy::Lap
+    };
+    for data_size in 1..=args.kb_max {
+        let data_size = 2u64.pow(data_size as u32) as usize * KB;
+        for subscribers in 1..=args.workers {
+            run_fan_out(
+                subscribers,
+                args.number,
+                data_size,
+                args.queue_capacity,
+                policy,
+                args.ready_timeout.into(),
+                true,
+            )?;
         }
-        Method::Unixdatagram => {
-            for data_size in 1..=args.kb_max {
-                let data_size = 2u64.pow(data_size as u32) as usize * KB;
-                let mut runner = UnixDatagramRunner::new(true, data_size);
-
-                core_affinity::set_for_current(core_affinity::CoreId { id: 1 });
-                cpu_warmup();
+    }
+    Ok(())
+}
 
-                runner.run(args.number, true);
-            }
+fn serve(method: ServeMethod) -> Result<(), IpcError> {
+    match method {
+        ServeMethod::Stdout(args) => run_consumer::<PipeWrapper>(args.config, args.endpoint),
+        ServeMethod::Fifo(args) => run_consumer::<FifoWrapper>(args.config, args.endpoint),
+        ServeMethod::Shmem(args) => run_consumer::<ShmemWrapper>(args.config, args.endpoint),
+        ServeMethod::ShmemEventFd(args) => {
+            run_consumer::<ShmemEventFdWrapper>(args.config, args.endpoint)
+        }
+        ServeMethod::ShmemRing(args) => {
+            run_consumer::<ShmemRingWrapper>(args.config, args.endpoint)
+        }
+        ServeMethod::SysvMsg(args) => run_consumer::<SysvMsgWrapper>(args.config, args.endpoint),
+        ServeMethod::SysvShm(args) => run_consumer::<SysvShmWrapper>(args.config, args.endpoint),
+        ServeMethod::Tcp(args) => run_consumer::<TcpStreamWrapper>(args.config, args.endpoint),
+        ServeMethod::Udp(args) => run_consumer::<UdpStreamWrapper>(args.config, args.endpoint),
+        ServeMethod::Iceoryx(args) => run_consumer::<IceoryxWrapper>(args.config, args.endpoint),
+        ServeMethod::Mmap(args) => run_consumer::<MmapWrapper>(args.config, args.endpoint),
+        ServeMethod::Mqueue(args) => run_consumer::<MqueueWrapper>(args.config, args.endpoint),
+        ServeMethod::UnixStream(args) => {
+            run_consumer::<UnixStreamWrapper>(args.config, args.endpoint)
         }
+        ServeMethod::UnixDatagram(args) => {
+            run_consumer::<UnixDatagramWrapper>(args.config, args.endpoint)
+        }
+        ServeMethod::QueueWorker(args) => run_worker(args),
+        ServeMethod::BroadcastSubscriber(args) => run_subscriber(args),
     }
 }
 
@@ -108,11 +183,19 @@ fn main() {
 enum Method {
     #[default]
     Stdout,
+    Fifo,
     Shmem,
+    Shmemevent,
+    Shmemring,
+    Shmemqueue,
+    Sysvmsg,
+    Sysvshm,
+    Broadcast,
     Tcp,
     Udp,
     Iceoryx,
     Mmap,
+    Mqueue,
     Unixstream,
     Unixdatagram,
 }
@@ -130,4 +213,111 @@ struct Cli {
 
     #[arg(short, long, action, default_value_t = 10)]
     kb_max: usize,
+
+    /// Send requests of random sizes up to the current size, for transports that frame messages
+    #[arg(long, action)]
+    mixed_sizes: bool,
+
+    /// Also run the async version of the stdout, tcp, udp, unixstream and unixdatagram methods
+    #[arg(long, action)]
+    with_async: bool,
+
+    /// Send requests ahead of their responses, for transports that can queue them (shmemring)
+    #[arg(long, action)]
+    streaming: bool,
+
+    /// Slots in each direction of the shmemring transport
+    #[arg(long, default_value_t = DEFAULT_RING_SLOTS)]
+    ring_slots: usize,
+
+    /// Most producers and consumers to scale up to with the shmemqueue method, or subscribers
+    /// with the broadcast method
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+
+    /// Messages the shmemqueue and broadcast rings can hold
+    #[arg(long, default_value_t = 64)]
+    queue_capacity: usize,
+
+    /// Make the broadcast publisher wait for slow subscribers rather than lapping them
+    #[arg(long, action)]
+    backpressure: bool,
+
+    /// How long to wait for the consumer to come up, e.g. "500ms" or "30s"
+    #[arg(long, default_value = "10s")]
+    ready_timeout: humantime::Duration,
+
+    /// How long to give the consumer to exit once it has been told to stop, before killing it
+    #[arg(long, default_value = "1s")]
+    shutdown_timeout: humantime::Duration,
+
+    /// How the shmem and mmap methods wait for the other side: spin, block, or adaptive:N to spin
+    /// N times and then block, e.g. "adaptive:1000"
+    #[arg(long, default_value_t)]
+    wait_strategy: WaitStrategy,
+
+    /// Fail with a timeout if any send or recv blocks for longer than this, e.g. "50ms"
+    #[arg(long)]
+    io_timeout: Option<humantime::Duration>,
+
+    /// Name for the socket files, mapped file and iceoryx services, rather than a unique one per
+    /// run. Runs which share a name can't overlap
+    #[arg(long)]
+    endpoint_name: Option<String>,
+}
+
+/// Runs the consumer side of a method, as spawned by the producer
+#[derive(Parser, Debug)]
+#[command(name = "ipc serve", bin_name = "ipc serve")]
+struct Serve {
+    #[command(subcommand)]
+    method: ServeMethod,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ServeMethod {
+    #[command(name = PipeWrapper::CONSUMER)]
+    Stdout(ServeArgs<<PipeWrapper as Transport>::Endpoint>),
+    #[command(name = FifoWrapper::CONSUMER)]
+    Fifo(ServeArgs<<FifoWrapper as Transport>::Endpoint>),
+    #[command(name = ShmemWrapper::CONSUMER)]
+    Shmem(ServeArgs<<ShmemWrapper as Transport>::Endpoint>),
+    #[command(name = ShmemEventFdWrapper::CONSUMER)]
+    ShmemEventFd(ServeArgs<<ShmemEventFdWrapper as Transport>::Endpoint>),
+    #[command(name = ShmemRingWrapper::CONSUMER)]
+    ShmemRing(ServeArgs<<ShmemRingWrapper as Transport>::Endpoint>),
+    #[command(name = SysvMsgWrapper::CONSUMER)]
+    SysvMsg(ServeArgs<<SysvMsgWrapper as Transport>::Endpoint>),
+    #[command(name = SysvShmWrapper::CONSUMER)]
+    SysvShm(ServeArgs<<SysvShmWrapper as Transport>::Endpoint>),
+    #[command(name = TcpStreamWrapper::CONSUMER)]
+    Tcp(ServeArgs<<TcpStreamWrapper as Transport>::Endpoint>),
+    #[command(name = UdpStreamWrapper::CONSUMER)]
+    Udp(ServeArgs<<UdpStreamWrapper as Transport>::Endpoint>),
+    #[command(name = IceoryxWrapper::CONSUMER)]
+    Iceoryx(ServeArgs<<IceoryxWrapper as Transport>::Endpoint>),
+    #[command(name = MmapWrapper::CONSUMER)]
+    Mmap(ServeArgs<<MmapWrapper as Transport>::Endpoint>),
+    #[command(name = MqueueWrapper::CONSUMER)]
+    Mqueue(ServeArgs<<MqueueWrapper as Transport>::Endpoint>),
+    #[command(name = UnixStreamWrapper::CONSUMER)]
+    UnixStream(ServeArgs<<UnixStreamWrapper as Transport>::Endpoint>),
+    #[command(name = UnixDatagramWrapper::CONSUMER)]
+    UnixDatagram(ServeArgs<<UnixDatagramWrapper as Transport>::Endpoint>),
+    /// Push or pop messages through a shared memory queue
+    #[command(name = WORKER)]
+    QueueWorker(WorkerArgs),
+    /// Read messages from a shared memory broadcast ring
+    #[command(name = SUBSCRIBER)]
+    BroadcastSubscriber(SubscriberArgs),
+}
+
+/// Echo the producer's requests back to it
+#[derive(clap::Args, Debug)]
+struct ServeArgs<E: Endpoint> {
+    #[command(flatten)]
+    config: TransportConfig,
+
+    #[command(flatten)]
+    endpoint: E,
 }
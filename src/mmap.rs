@@ -1,170 +1,236 @@
-use std::{
-    fs::OpenOptions,
-    path::PathBuf,
-    process::{Child, Command},
-    thread::sleep,
-    time::{Duration, Instant},
+use std::{fs::OpenOptions, path::PathBuf, process::Child, sync::atomic::Ordering, time::Duration};
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::futex::{FutexEvent, WaitStrategy};
+use crate::liveness::{PeerWatch, LIVENESS_INTERVAL};
+use crate::segment::{SegmentHeader, DATA_START};
+use crate::transport::{
+    endpoint_path, wait_for_consumer, NamedEndpoint, Runner, Transport, TransportConfig,
 };
-
 use memmap2::MmapMut;
-use raw_sync::{
-    events::{BusyEvent, EventImpl, EventInit, EventState},
-    Timeout,
-};
-use std::io::Write;
 
-use crate::{get_payload, ExecutionResult, KB};
+// The file is laid out as described in the segment module: a header, holding the producer's and
+// the consumer's events, and then the message itself
 
 pub struct MmapWrapper {
     pub mmap: MmapMut,
+    pub name: String,
+    pub path: PathBuf,
     pub owner: bool,
-    pub our_event: Box<dyn EventImpl>,
-    pub their_event: Box<dyn EventImpl>,
+    pub wait_strategy: WaitStrategy,
     pub data_start: usize,
     pub data_size: usize,
+    // Set once both sides have attached
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
 }
 
 impl MmapWrapper {
-    pub fn new(owner: bool, data_size: usize) -> Self {
-        let data_size = data_size + 4;
-        let path: PathBuf = "/tmp/mmap_data.txt".into();
+    pub fn new(
+        name: &str,
+        owner: bool,
+        max_message_size: usize,
+        wait_strategy: WaitStrategy,
+    ) -> Result<Self> {
+        let data_size = max_message_size + DATA_START;
+        let path = endpoint_path(name, ".mmap");
+        // Only the owner sizes the file, the consumer takes it as it finds it
         let file = OpenOptions::new()
             .read(true)
             .write(true)
-            .create(true)
-            .open(&path)
-            .unwrap();
-        file.set_len(data_size as u64).unwrap();
-
-        let mut mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
-        let bytes = mmap.as_mut();
-
-        // The two events are locks - one for each side. Each side activates the lock while it's
-        // writing, and then unlocks when the data can be read
-        let ((our_event, lock_bytes_ours), (their_event, lock_bytes_theirs)) = unsafe {
-            if owner {
-                (
-                    BusyEvent::new(bytes.get_mut(0).unwrap(), true).unwrap(),
-                    BusyEvent::new(bytes.get_mut(2).unwrap(), true).unwrap(),
-                )
-            } else {
-                (
-                    // If we're not the owner, the events have been created already
-                    BusyEvent::from_existing(bytes.get_mut(2).unwrap()).unwrap(),
-                    BusyEvent::from_existing(bytes.get_mut(0).unwrap()).unwrap(),
-                )
-            }
-        };
+            .create(owner)
+            .truncate(false)
+            .open(&path)?;
+        if owner {
+            file.set_len(data_size as u64)?;
+        }
 
-        // Confirm that we've correctly indexed two bytes for each lock
-        assert!(lock_bytes_ours <= 2);
-        assert!(lock_bytes_theirs <= 2);
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        let header = unsafe { SegmentHeader::at(mmap.as_ptr(), mmap.len())? };
+        // If we're not the owner, the header and the events have been set up already
         if owner {
-            our_event.set(EventState::Clear).unwrap();
-            their_event.set(EventState::Clear).unwrap();
+            header.init(max_message_size);
+        } else {
+            header.validate(mmap.len(), max_message_size)?;
         }
 
-        Self {
+        let wrapper = Self {
             mmap,
+            name: name.to_string(),
+            path,
             owner,
-            our_event,
-            their_event,
-            data_start: 4,
+            wait_strategy,
+            data_start: DATA_START,
             data_size,
+            peer: None,
+            timeout: None,
+        };
+        if !owner {
+            wrapper
+                .header()
+                .pid(false)
+                .store(std::process::id(), Ordering::Release);
+        }
+        Ok(wrapper)
+    }
+
+    fn header(&self) -> &SegmentHeader {
+        unsafe { &*(self.mmap.as_ptr() as *const SegmentHeader) }
+    }
+
+    // The events are locks - one for each side. Each side activates its lock while it's writing,
+    // and then unlocks when the data can be read
+    fn our_event(&self) -> &FutexEvent {
+        self.header().event(self.owner)
+    }
+
+    fn their_event(&self) -> &FutexEvent {
+        self.header().event(!self.owner)
+    }
+
+    // Start watching the other side, once it has attached and recorded its pid
+    fn watch_peer(&mut self) {
+        let peer_pid = self.header().pid(!self.owner).load(Ordering::Acquire);
+        if peer_pid != 0 {
+            self.peer = Some(PeerWatch::new(peer_pid));
         }
     }
 
     pub fn signal_start(&mut self) {
-        self.our_event.set(EventState::Clear).unwrap()
+        self.our_event().reset();
     }
 
     pub fn signal_finished(&mut self) {
-        self.our_event.set(EventState::Signaled).unwrap()
+        self.our_event().set();
     }
 
-    pub fn write(&mut self, data: &[u8]) {
-        (&mut self.mmap[self.data_start..]).write(data).unwrap();
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let capacity = self.data_size - self.data_start;
+        if data.len() > capacity {
+            return Err(IpcError::SizeMismatch {
+                expected: capacity,
+                actual: data.len(),
+            });
+        }
+        self.mmap[self.data_start..self.data_start + data.len()].copy_from_slice(data);
+        self.header()
+            .length()
+            .store(data.len() as u32, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn read(&self) -> &[u8] {
-        &self.mmap.as_ref()[self.data_start..self.data_size]
+    // Only the bytes of the last message written, not the whole file
+    pub fn read(&self) -> Result<&[u8]> {
+        let bytes = self.mmap.as_ref();
+        let len = self.header().length().load(Ordering::Relaxed) as usize;
+        bytes
+            .get(self.data_start..self.data_start + len)
+            .filter(|_| self.data_start + len <= self.data_size)
+            .ok_or(IpcError::SizeMismatch {
+                expected: self.data_size - self.data_start,
+                actual: len,
+            })
     }
 }
 
-pub struct MmapRunner {
-    child_proc: Option<Child>,
-    wrapper: MmapWrapper,
-    data_size: usize,
-    request_data: Vec<u8>,
-    response_data: Vec<u8>,
-}
+impl Transport for MmapWrapper {
+    const CONSUMER: &'static str = "mmap";
 
-impl MmapRunner {
-    pub fn new(start_child: bool, data_size: usize) -> Self {
-        let wrapper = MmapWrapper::new(true, data_size);
-
-        let exe = crate::executable_path("mmap_consumer");
-        let child_proc = if start_child {
-            let res = Some(
-                Command::new(exe)
-                    .args(&[data_size.to_string()])
-                    .spawn()
-                    .unwrap(),
-            );
-            // Clumsy sleep here but it allows the child proc to spawn without it having to offer
-            // us a ready event
-            sleep(Duration::from_secs(2));
-            res
-        } else {
-            None
-        };
+    const VARIABLE_LENGTH: bool = true;
 
-        let (request_data, response_data) = get_payload(data_size);
+    type Listener = MmapWrapper;
 
-        Self {
-            child_proc,
-            wrapper,
-            data_size,
-            request_data,
-            response_data,
-        }
+    type Endpoint = NamedEndpoint;
+
+    fn name(config: &TransportConfig) -> String {
+        format!("Memory mapped file - {} wait", config.wait_strategy)
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        MmapWrapper::new(
+            &config.resolve_endpoint_name(),
+            true,
+            config.max_message_size,
+            config.wait_strategy,
+        )
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<NamedEndpoint> {
+        Ok(NamedEndpoint {
+            name: listener.name.clone(),
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer signals its event once it has attached
+        wait_for_consumer(child, config.ready_timeout, || {
+            Ok(listener.their_event().try_take())
+        })?;
+        let mut transport = listener;
+        transport.watch_peer();
+        Ok(transport)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &NamedEndpoint) -> Result<Self> {
+        let mut wrapper = MmapWrapper::new(
+            &endpoint.name,
+            false,
+            config.max_message_size,
+            config.wait_strategy,
+        )?;
+        wrapper.watch_peer();
+        // Tell the producer we have attached
+        wrapper.signal_finished();
+        Ok(wrapper)
     }
 
-    pub fn run(&mut self, n: usize, print: bool) {
-        let instant = Instant::now();
-        for _ in 0..n {
-            // Activate our lock in preparation for writing
-            self.wrapper.signal_start();
-            self.wrapper.write(&self.request_data);
-            // Unlock after writing
-            self.wrapper.signal_finished();
-            // Wait for their lock to be released so we can read
-            if self.wrapper.their_event.wait(Timeout::Infinite).is_ok() {
-                let str = self.wrapper.read();
-
-                #[cfg(debug_assertions)]
-                if str.ne(&self.response_data) {
-                    panic!("Sent request didn't get response")
-                }
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // The other side has always read the last message before it replies, so a send never waits
+    fn send_timeout(&mut self, data: &[u8], _timeout: Option<Duration>) -> Result<()> {
+        // Activate our lock in preparation for writing
+        self.signal_start();
+        self.write(data)?;
+        // Unlock after writing
+        self.signal_finished();
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        // Wait for their lock to be released so we can read, giving up if they have gone or we
+        // run out of time
+        loop {
+            let wait = match deadline::remaining(deadline)? {
+                Some(left) => left.min(LIVENESS_INTERVAL),
+                None => LIVENESS_INTERVAL,
+            };
+            if self.their_event().wait(self.wait_strategy, wait) {
+                break;
+            }
+            if let Some(peer) = &self.peer {
+                peer.ensure_alive()?;
             }
         }
-        let elapsed = instant.elapsed();
-
-        if print {
-            let res = ExecutionResult::new(
-                format!("Memory mapped file - {}KB", self.data_size / KB),
-                elapsed,
-                n,
-            );
-            res.print_info();
-        }
+        self.read()
     }
-}
 
-impl Drop for MmapRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut child) = self.child_proc {
-            child.kill().expect("Unable to kill child process")
+    fn close(&mut self) {
+        if self.owner {
+            let _ = std::fs::remove_file(&self.path);
         }
     }
 }
+
+pub type MmapRunner = Runner<MmapWrapper>;
@@ -0,0 +1,463 @@
+// POSIX message queues, one carrying requests and one carrying responses. A queue's messages are
+// sized from `max_message_size`, but the kernel caps them at /proc/sys/fs/mqueue/msgsize_max, so a
+// bigger message goes out as several queue messages. The first starts with the message's length:
+//
+// | message length: u32 | payload... |  | payload... |  ...
+//
+// A queue keeps its messages in order and each has a single sender and receiver, so the receiver
+// just reads until it has the whole length. The consumer's ready signal is a bare queue message,
+// which is never the start of a real one.
+//
+// Nothing tells a blocked sender or receiver that the other side has gone, so each side watches
+// the other's pid while it waits, as the shared memory transports do.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::liveness::{PeerWatch, LIVENESS_INTERVAL};
+use crate::transport::{wait_for_consumer, Endpoint, Runner, Transport, TransportConfig};
+use std::ffi::CString;
+use std::io;
+use std::process::Child;
+use std::time::{Duration, Instant};
+
+const HEADER_LEN: usize = 4;
+// Only the owner can use the queues
+const QUEUE_MODE: libc::mode_t = 0o600;
+// Used if the limits can't be read, they're the kernel's defaults
+const DEFAULT_MSGSIZE_MAX: usize = 8192;
+const DEFAULT_MSG_MAX: usize = 10;
+
+/// The queues' base name and the producer's pid, for the consumer to open them and watch it
+#[derive(Debug, Clone, clap::Args)]
+pub struct MqueueEndpoint {
+    #[arg(long)]
+    pub name: String,
+    #[arg(long)]
+    pub producer_pid: u32,
+}
+
+impl Endpoint for MqueueEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec![
+            "--name".to_string(),
+            self.name.clone(),
+            "--producer-pid".to_string(),
+            self.producer_pid.to_string(),
+        ]
+    }
+}
+
+// One of the system's message queue limits
+fn queue_limit(name: &str, default: usize) -> usize {
+    std::fs::read_to_string(format!("/proc/sys/fs/mqueue/{}", name))
+        .ok()
+        .and_then(|limit| limit.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+// Queue names are a slash followed by the name
+fn queue_name(name: &str, direction: &str) -> Result<CString> {
+    CString::new(format!("/{}-{}", name, direction))
+        .map_err(|_| IpcError::protocol(format!("Queue name {:?} contains a nul", name)))
+}
+
+// The CLOCK_REALTIME time `timeout` from now, which is what the timed queue calls take
+fn realtime_after(timeout: Duration) -> libc::timespec {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+    let nanos = now.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+    libc::timespec {
+        tv_sec: now.tv_sec
+            + timeout.as_secs() as libc::time_t
+            + (nanos / 1_000_000_000) as libc::time_t,
+        tv_nsec: (nanos % 1_000_000_000) as libc::c_long,
+    }
+}
+
+struct Queue {
+    mqd: libc::mqd_t,
+    name: CString,
+    // Set while the name is ours to unlink, from creating the queue until it's unlinked
+    owner: bool,
+    // Largest message the queue takes
+    msg_size: usize,
+}
+
+impl Queue {
+    // Create the queue, replacing one a crashed run under the same name left behind
+    fn create(name: CString, msg_size: usize) -> Result<Self> {
+        unsafe { libc::mq_unlink(name.as_ptr()) };
+        let mut attr: libc::mq_attr = unsafe { std::mem::zeroed() };
+        attr.mq_maxmsg = queue_limit("msg_max", DEFAULT_MSG_MAX) as libc::c_long;
+        attr.mq_msgsize = msg_size as libc::c_long;
+        let mqd = unsafe {
+            libc::mq_open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                QUEUE_MODE,
+                &attr as *const libc::mq_attr,
+            )
+        };
+        Self::opened(mqd, name, true)
+    }
+
+    fn open(name: CString) -> Result<Self> {
+        let mqd = unsafe { libc::mq_open(name.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        Self::opened(mqd, name, false)
+    }
+
+    // Finish opening, taking the message size from the queue so both sides agree on it
+    fn opened(mqd: libc::mqd_t, name: CString, owner: bool) -> Result<Self> {
+        if mqd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut queue = Self {
+            mqd,
+            name,
+            owner,
+            msg_size: 0,
+        };
+        let mut attr: libc::mq_attr = unsafe { std::mem::zeroed() };
+        if unsafe { libc::mq_getattr(mqd, &mut attr) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        queue.msg_size = attr.mq_msgsize as usize;
+        if queue.msg_size <= HEADER_LEN {
+            return Err(IpcError::protocol(format!(
+                "Queue messages of {} bytes can't hold a header",
+                queue.msg_size
+            )));
+        }
+        Ok(queue)
+    }
+
+    fn send(&self, msg: &[u8], deadline: Option<Instant>, peer: Option<&PeerWatch>) -> Result<()> {
+        timed(deadline, peer, |at| unsafe {
+            libc::mq_timedsend(self.mqd, msg.as_ptr().cast(), msg.len(), 0, at) as libc::ssize_t
+        })?;
+        Ok(())
+    }
+
+    // Receive one queue message into `buf`, which must hold `msg_size` bytes
+    fn recv(
+        &self,
+        buf: &mut [u8],
+        deadline: Option<Instant>,
+        peer: Option<&PeerWatch>,
+    ) -> Result<usize> {
+        timed(deadline, peer, |at| unsafe {
+            libc::mq_timedreceive(
+                self.mqd,
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                std::ptr::null_mut(),
+                at,
+            )
+        })
+    }
+
+    // Receive without waiting, None if the queue is empty
+    fn try_recv(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        loop {
+            // A time already passed, so the call returns straight away
+            let now = realtime_after(Duration::ZERO);
+            let res = unsafe {
+                libc::mq_timedreceive(
+                    self.mqd,
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                    std::ptr::null_mut(),
+                    &now,
+                )
+            };
+            if res >= 0 {
+                return Ok(Some(res as usize));
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ETIMEDOUT) => return Ok(None),
+                Some(libc::EINTR) => {}
+                _ => return Err(e.into()),
+            }
+        }
+    }
+
+    // Only once, as another run could have taken the name since
+    fn unlink(&mut self) {
+        if std::mem::take(&mut self.owner) {
+            unsafe { libc::mq_unlink(self.name.as_ptr()) };
+        }
+    }
+}
+
+// Queues outlive the process, so one the producer fails with before it's closed, such as while
+// starting the consumer, is still unlinked
+impl Drop for Queue {
+    fn drop(&mut self) {
+        unsafe { libc::mq_close(self.mqd) };
+        self.unlink();
+    }
+}
+
+// Run a timed queue call, giving it at most LIVENESS_INTERVAL at a time so a dead peer is noticed
+fn timed(
+    deadline: Option<Instant>,
+    peer: Option<&PeerWatch>,
+    mut call: impl FnMut(&libc::timespec) -> libc::ssize_t,
+) -> Result<usize> {
+    loop {
+        let wait = match deadline::remaining(deadline)? {
+            Some(left) => left.min(LIVENESS_INTERVAL),
+            None => LIVENESS_INTERVAL,
+        };
+        let res = call(&realtime_after(wait));
+        if res >= 0 {
+            return Ok(res as usize);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ETIMEDOUT) => {
+                if let Some(peer) = peer {
+                    peer.ensure_alive()?;
+                }
+            }
+            Some(libc::EINTR) => {}
+            _ => return Err(e.into()),
+        }
+    }
+}
+
+pub struct MqueueWrapper {
+    pub name: String,
+    pub owner: bool,
+    tx: Queue,
+    rx: Queue,
+    max_message_size: usize,
+    // A queue message on its way out or in
+    chunk: Vec<u8>,
+    // The whole message received last
+    buf: Vec<u8>,
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
+}
+
+impl MqueueWrapper {
+    /// Create the request and response queues, for the producer
+    pub fn create(name: &str, max_message_size: usize) -> Result<Self> {
+        let msg_size =
+            (HEADER_LEN + max_message_size).min(queue_limit("msgsize_max", DEFAULT_MSGSIZE_MAX));
+        let tx = Queue::create(queue_name(name, "requests")?, msg_size)?;
+        let rx = Queue::create(queue_name(name, "responses")?, msg_size)?;
+        Ok(Self::new(name, true, tx, rx, max_message_size))
+    }
+
+    /// Open the producer's queues, for the consumer
+    pub fn open(name: &str, max_message_size: usize) -> Result<Self> {
+        let tx = Queue::open(queue_name(name, "responses")?)?;
+        let rx = Queue::open(queue_name(name, "requests")?)?;
+        Ok(Self::new(name, false, tx, rx, max_message_size))
+    }
+
+    fn new(name: &str, owner: bool, tx: Queue, rx: Queue, max_message_size: usize) -> Self {
+        let chunk_len = tx.msg_size.max(rx.msg_size);
+        Self {
+            name: name.to_string(),
+            owner,
+            tx,
+            rx,
+            max_message_size,
+            chunk: vec![0; chunk_len],
+            buf: vec![0; max_message_size],
+            peer: None,
+            timeout: None,
+        }
+    }
+
+    /// Most bytes one queue message carries, header included. Messages bigger than this are
+    /// split up
+    pub fn queue_message_size(&self) -> usize {
+        self.tx.msg_size
+    }
+}
+
+impl Transport for MqueueWrapper {
+    const CONSUMER: &'static str = "mqueue";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = MqueueWrapper;
+
+    type Endpoint = MqueueEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "POSIX message queue".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        MqueueWrapper::create(&config.resolve_endpoint_name(), config.max_message_size)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(MqueueEndpoint {
+            name: listener.name.clone(),
+            producer_pid: std::process::id(),
+        })
+    }
+
+    fn accept(
+        mut listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let consumer_pid = child.as_ref().map(|child| child.id());
+        // The consumer sends a bare queue message once it has opened the queues
+        let MqueueWrapper { rx, chunk, .. } = &mut listener;
+        let ready = wait_for_consumer(child, config.ready_timeout, || {
+            match rx.try_recv(chunk)? {
+                None => Ok(false),
+                Some(0) => Ok(true),
+                Some(_) => Err(IpcError::protocol("Expected the consumer's ready signal")),
+            }
+        });
+        if let Err(e) = ready {
+            listener.close();
+            return Err(e);
+        }
+        listener.peer = consumer_pid.map(PeerWatch::new);
+        Ok(listener)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let mut wrapper = MqueueWrapper::open(&endpoint.name, config.max_message_size)?;
+        wrapper.peer = Some(PeerWatch::new(endpoint.producer_pid));
+        wrapper
+            .tx
+            .send(&[], deadline::deadline(Some(config.ready_timeout)), None)?;
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        if data.len() > self.max_message_size || data.len() > u32::MAX as usize {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: data.len(),
+            });
+        }
+        let deadline = deadline::deadline(timeout);
+        let msg_size = self.tx.msg_size;
+        // The first queue message carries the length, and as much payload as fits after it
+        let first = data.len().min(msg_size - HEADER_LEN);
+        self.chunk[..HEADER_LEN].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.chunk[HEADER_LEN..HEADER_LEN + first].copy_from_slice(&data[..first]);
+        self.tx.send(
+            &self.chunk[..HEADER_LEN + first],
+            deadline,
+            self.peer.as_ref(),
+        )?;
+        for rest in data[first..].chunks(msg_size) {
+            self.tx.send(rest, deadline, self.peer.as_ref())?;
+        }
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        let peer = self.peer.as_ref();
+        let len = self.rx.recv(&mut self.chunk, deadline, peer)?;
+        if len < HEADER_LEN {
+            return Err(IpcError::protocol(format!(
+                "Queue message of {} bytes is too short for a message header",
+                len
+            )));
+        }
+        let total = u32::from_le_bytes(self.chunk[..HEADER_LEN].try_into().unwrap()) as usize;
+        if total > self.max_message_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: total,
+            });
+        }
+        let mut received = len - HEADER_LEN;
+        if received > total {
+            return Err(IpcError::protocol(format!(
+                "Message is longer than its {} bytes",
+                total
+            )));
+        }
+        self.buf[..received].copy_from_slice(&self.chunk[HEADER_LEN..len]);
+        while received < total {
+            let len = self
+                .rx
+                .recv(&mut self.chunk, deadline, peer)
+                .map_err(deadline::partway)?;
+            if received + len > total {
+                return Err(IpcError::protocol(format!(
+                    "Message is longer than its {} bytes",
+                    total
+                )));
+            }
+            self.buf[received..received + len].copy_from_slice(&self.chunk[..len]);
+            received += len;
+        }
+        Ok(&self.buf[..total])
+    }
+
+    fn close(&mut self) {
+        self.tx.unlink();
+        self.rx.unlink();
+    }
+}
+
+pub type MqueueRunner = Runner<MqueueWrapper>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+    const TIMEOUT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn timeout_partway_through_a_message_is_a_protocol_error() -> Result<()> {
+        let name = format!("ipc-test-mqueue-partway-{}", std::process::id());
+        let mut producer = MqueueWrapper::create(&name, MAX_MESSAGE_SIZE)?;
+        let mut consumer = MqueueWrapper::open(&name, MAX_MESSAGE_SIZE)?;
+        let msg_size = producer.queue_message_size();
+        assert!(msg_size < MAX_MESSAGE_SIZE, "The message has to be split");
+
+        // Nothing sent yet, which can be tried again
+        assert!(matches!(
+            consumer.recv_timeout(Some(TIMEOUT)),
+            Err(IpcError::Timeout)
+        ));
+
+        // A whole message comes back in one piece
+        let data: Vec<u8> = (0..3 * msg_size).map(|i| i as u8).collect();
+        producer.send_timeout(&data, Some(TIMEOUT))?;
+        assert_eq!(consumer.recv_timeout(Some(TIMEOUT))?, &data[..]);
+
+        // Only the first queue message of one goes out
+        let mut first = vec![0; msg_size];
+        first[..HEADER_LEN].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        producer.tx.send(&first, None, None)?;
+        let partway = consumer.recv_timeout(Some(TIMEOUT));
+        producer.close();
+        assert!(
+            matches!(partway, Err(IpcError::Protocol(_))),
+            "Expected a protocol error, got {:?}",
+            partway
+        );
+        Ok(())
+    }
+}
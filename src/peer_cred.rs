@@ -0,0 +1,189 @@
+// Checking who is on the other end of a unix socket. Anyone on the host who can reach the socket
+// file can connect or send to it, so both sides check the other's credentials against a policy
+// before trusting anything it sends.
+
+use crate::error::{IpcError, Result};
+use std::fmt::{Display, Formatter};
+use std::io::{self, IoSliceMut};
+use std::mem::{size_of, size_of_val};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Socket files are only usable by their owner, unless configured otherwise
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+/// The process on the other end of a unix socket, as the kernel reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Display for PeerCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pid {} uid {} gid {}", self.pid, self.uid, self.gid)
+    }
+}
+
+/// Which peers a unix socket transport accepts. A peer must match every list which isn't empty,
+/// so an empty policy accepts anyone
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerPolicy {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub pids: Vec<u32>,
+}
+
+impl PeerPolicy {
+    /// Accept any peer
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    /// Only accept peers running as our effective user
+    pub fn same_user() -> Self {
+        Self::any().allow_uid(unsafe { libc::geteuid() })
+    }
+
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.uids.push(uid);
+        self
+    }
+
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.gids.push(gid);
+        self
+    }
+
+    pub fn allow_pid(mut self, pid: u32) -> Self {
+        self.pids.push(pid);
+        self
+    }
+
+    /// Fails with [`IpcError::PeerRejected`] unless the policy allows `peer`
+    pub fn check(&self, peer: &PeerCredentials) -> Result<()> {
+        let allows = |allowed: &[u32], id: u32| allowed.is_empty() || allowed.contains(&id);
+        if allows(&self.uids, peer.uid)
+            && allows(&self.gids, peer.gid)
+            && allows(&self.pids, peer.pid)
+        {
+            Ok(())
+        } else {
+            Err(IpcError::PeerRejected(*peer))
+        }
+    }
+}
+
+/// Who is on the other end of a connected unix stream socket, as of when it connected
+pub(crate) fn stream_peer(socket: BorrowedFd<'_>) -> Result<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(credentials(&cred))
+}
+
+/// Have the kernel attach the sender's credentials to each datagram this socket receives
+pub(crate) fn pass_credentials(socket: BorrowedFd<'_>, enable: bool) -> Result<()> {
+    let enable = enable as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            &enable as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Who sent a datagram, and from which socket file
+pub(crate) struct Sender {
+    pub(crate) credentials: Option<PeerCredentials>,
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// Read one datagram into `buf`, on a socket with [`pass_credentials`] enabled
+pub(crate) fn recv_from_sender(socket: BorrowedFd<'_>, buf: &mut [u8]) -> io::Result<Sender> {
+    let mut address: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    // Room for the control message header and a ucred, as words so it's aligned for cmsghdr
+    let mut control = [0usize; 8];
+    let mut iov = [IoSliceMut::new(buf)];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = (&mut address as *mut libc::sockaddr_un).cast();
+    msg.msg_namelen = size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    // IoSliceMut is guaranteed to match iovec on unix
+    msg.msg_iov = iov.as_mut_ptr().cast();
+    msg.msg_iovlen = iov.len() as _;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&control) as _;
+
+    loop {
+        if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    let mut sender = Sender {
+        credentials: None,
+        path: None,
+    };
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS
+            {
+                let cred = libc::CMSG_DATA(cmsg).cast::<libc::ucred>().read_unaligned();
+                sender.credentials = Some(credentials(&cred));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    // An unbound sender has no address, and an abstract one starts with a nul
+    let path_start = size_of::<libc::sa_family_t>();
+    let path_len = (msg.msg_namelen as usize).saturating_sub(path_start);
+    let path = &address.sun_path[..path_len.min(address.sun_path.len())];
+    let path = path.split(|c| *c == 0).next().unwrap_or_default();
+    if !path.is_empty() {
+        let path: Vec<u8> = path.iter().map(|c| *c as u8).collect();
+        sender.path = Some(PathBuf::from(std::ffi::OsStr::from_bytes(&path)));
+    }
+    Ok(sender)
+}
+
+/// Limit who can use the socket file at `path` to what `mode` allows. Between bind and this
+/// anyone could reach it, which the credential checks cover
+pub(crate) fn restrict_socket(path: &Path, mode: u32) -> Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+fn credentials(cred: &libc::ucred) -> PeerCredentials {
+    PeerCredentials {
+        pid: cred.pid as u32,
+        uid: cred.uid,
+        gid: cred.gid,
+    }
+}
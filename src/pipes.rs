@@ -1,71 +1,243 @@
-use crate::{get_payload, ExecutionResult, KB};
-use std::io::{Read, Write};
-use std::process::{Child, Command, Stdio};
-use std::time::Instant;
-
-pub struct PipeRunner {
-    pipe_proc: Child,
-    data_size: usize,
-    request_data: Vec<u8>,
-    response_data: Vec<u8>,
+use crate::async_transport::{wait_for_consumer_async, AsyncRunner, AsyncTransport};
+use crate::deadline::{self, WithDeadline};
+use crate::error::{IpcError, Result};
+use crate::framing::{read_frame, read_frame_async, write_frame, write_frame_async, FrameProgress};
+use crate::transport::{wait_for_consumer, NoEndpoint, Runner, Transport, TransportConfig};
+use std::fs::File;
+use std::io::{stdin, stdout, Read, Write};
+use std::os::fd::AsFd;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const READY: u8 = 1;
+
+/// The read end of a pipe, which can be polled to time out reads
+pub trait PipeRead: Read + AsFd {}
+
+impl<T: Read + AsFd> PipeRead for T {}
+
+/// The write end of a pipe, which can be polled to time out writes
+pub trait PipeWrite: Write + AsFd {}
+
+impl<T: Write + AsFd> PipeWrite for T {}
+
+pub struct PipeWrapper {
+    pub input: Box<dyn PipeWrite>,
+    pub output: Box<dyn PipeRead>,
+    buf: Vec<u8>,
+    timeout: Option<Duration>,
 }
 
-impl PipeRunner {
-    pub fn new(data_size: usize) -> PipeRunner {
-        // let output_dir = PathBuf::from(env::var("CARGO_TARGET_DIR").unwrap());
-        // let output_dir = PathBuf::new();
-        // let exe = output_dir.join("pipes_consumer.exe");
-        let exe = crate::executable_path("pipes_consumer");
-
-        let (request_data, response_data) = get_payload(data_size);
-
-        PipeRunner {
-            pipe_proc: Command::new(exe)
-                .args(&[data_size.to_string()])
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .unwrap(),
-            data_size,
-            request_data,
-            response_data,
-        }
-    }
-
-    pub fn run_inner(&mut self, n: usize) {
-        if let Some(ref mut pipes_input) = self.pipe_proc.stdin {
-            if let Some(ref mut pipes_output) = self.pipe_proc.stdout {
-                let mut buf = vec![0; self.data_size];
-                for _ in 0..n {
-                    pipes_input.write(&self.request_data).unwrap();
-                    pipes_output.read_exact(&mut buf).unwrap();
-
-                    #[cfg(debug_assertions)]
-                    if buf.ne(&self.response_data) {
-                        panic!("Unexpected response {}", String::from_utf8_lossy(&buf))
-                    }
-                }
+impl PipeWrapper {
+    // The producer talks to the child's stdin/stdout
+    pub fn from_child(child: &mut Child, max_message_size: usize) -> Result<Self> {
+        let output = child
+            .stdout
+            .take()
+            .ok_or_else(|| IpcError::protocol("Consumer stdout isn't piped"))?;
+        Self::from_child_output(child, output, max_message_size)
+    }
+
+    fn from_child_output(
+        child: &mut Child,
+        output: ChildStdout,
+        max_message_size: usize,
+    ) -> Result<Self> {
+        let input = child
+            .stdin
+            .take()
+            .ok_or_else(|| IpcError::protocol("Consumer stdin isn't piped"))?;
+        Ok(Self {
+            input: Box::new(input),
+            output: Box::new(output),
+            buf: vec![0; max_message_size],
+            timeout: None,
+        })
+    }
+
+    // The consumer talks over its own stdin/stdout. It uses its own handles on them rather than
+    // the std ones, whose buffering would hide data from the poll a timed read waits on
+    pub fn from_stdio(max_message_size: usize) -> Result<Self> {
+        Ok(Self {
+            input: Box::new(File::from(stdout().as_fd().try_clone_to_owned()?)),
+            output: Box::new(File::from(stdin().as_fd().try_clone_to_owned()?)),
+            buf: vec![0; max_message_size],
+            timeout: None,
+        })
+    }
+}
+
+impl Transport for PipeWrapper {
+    const CONSUMER: &'static str = "stdout";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = ();
+
+    type Endpoint = NoEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "Stdin/stdout".to_string()
+    }
+
+    fn listen(_config: &TransportConfig) -> Result<Self::Listener> {
+        Ok(())
+    }
+
+    fn endpoint(_listener: &Self::Listener) -> Result<NoEndpoint> {
+        Ok(NoEndpoint {})
+    }
+
+    fn prepare_consumer(_listener: &Self::Listener, command: &mut Command) -> Result<()> {
+        command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        Ok(())
+    }
+
+    fn accept(
+        _listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let child =
+            child.ok_or_else(|| IpcError::protocol("Pipes need a child process to talk to"))?;
+
+        // The consumer writes a ready byte to its stdout, which is only read once a poll says it's
+        // there, so the wait never blocks
+        let mut output = child
+            .stdout
+            .take()
+            .ok_or_else(|| IpcError::protocol("Consumer stdout isn't piped"))?;
+        wait_for_consumer(Some(&mut *child), config.ready_timeout, || {
+            match deadline::wait_readable(output.as_fd(), deadline::deadline(Some(Duration::ZERO)))
+            {
+                Ok(()) => {}
+                Err(IpcError::Timeout) => return Ok(false),
+                Err(e) => return Err(e),
             }
-        }
-    }
-
-    pub fn run(&mut self, n: usize, print: bool) {
-        let instant = Instant::now();
-        self.run_inner(n);
-        let elapsed = instant.elapsed();
-        if print {
-            let res = ExecutionResult::new(
-                format!("Stdin/stdout - {}KB", self.data_size / KB),
-                elapsed,
-                n,
-            );
-            res.print_info()
-        }
+            let mut ready = [0; 1];
+            match output.read(&mut ready)? {
+                0 => Err(IpcError::PeerGone),
+                _ if ready[0] == READY => Ok(true),
+                _ => Err(IpcError::protocol("Expected the consumer's ready byte")),
+            }
+        })?;
+
+        PipeWrapper::from_child_output(child, output, config.max_message_size)
+    }
+
+    fn connect(config: &TransportConfig, _endpoint: &NoEndpoint) -> Result<Self> {
+        let mut wrapper = PipeWrapper::from_stdio(config.max_message_size)?;
+        // Sent bare rather than framed, the producer reads it before any framing starts
+        wrapper.input.write_all(&[READY])?;
+        wrapper.input.flush()?;
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let mut input = WithDeadline::new(&mut *self.input, timeout);
+        write_frame(&mut input, data, self.buf.len())?;
+        input.flush()?;
+        Ok(())
     }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let mut output = WithDeadline::new(&mut *self.output, timeout);
+        let len = read_frame(&mut output, &mut self.buf)?;
+        Ok(&self.buf[..len])
+    }
+}
+
+pub type PipeRunner = Runner<PipeWrapper>;
+
+pub struct AsyncPipeWrapper {
+    pub input: Box<dyn AsyncWrite + Unpin>,
+    pub output: Box<dyn AsyncRead + Unpin>,
+    buf: Vec<u8>,
+    progress: FrameProgress,
 }
 
-impl Drop for PipeRunner {
-    fn drop(&mut self) {
-        self.pipe_proc.kill().unwrap();
+impl AsyncTransport for AsyncPipeWrapper {
+    type Blocking = PipeWrapper;
+
+    type Listener = ();
+
+    async fn listen(_config: &TransportConfig) -> Result<Self::Listener> {
+        Ok(())
+    }
+
+    fn endpoint(_listener: &Self::Listener) -> Result<NoEndpoint> {
+        Ok(NoEndpoint {})
+    }
+
+    fn prepare_consumer(_listener: &Self::Listener, command: &mut Command) -> Result<()> {
+        command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        Ok(())
+    }
+
+    async fn accept(
+        _listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let child =
+            child.ok_or_else(|| IpcError::protocol("Pipes need a child process to talk to"))?;
+        let output = child
+            .stdout
+            .take()
+            .ok_or_else(|| IpcError::protocol("Consumer stdout isn't piped"))?;
+        let input = child
+            .stdin
+            .take()
+            .ok_or_else(|| IpcError::protocol("Consumer stdin isn't piped"))?;
+        let mut output = tokio::process::ChildStdout::from_std(output)?;
+        let input = tokio::process::ChildStdin::from_std(input)?;
+
+        // The consumer writes a ready byte to its stdout
+        wait_for_consumer_async(Some(child), config.ready_timeout, async {
+            Ok(output.read_exact(&mut [0; 1]).await?)
+        })
+        .await?;
+
+        Ok(Self {
+            input: Box::new(input),
+            output: Box::new(output),
+            buf: vec![0; config.max_message_size],
+            progress: FrameProgress::default(),
+        })
+    }
+
+    async fn connect(config: &TransportConfig, _endpoint: &NoEndpoint) -> Result<Self> {
+        let mut wrapper = Self {
+            input: Box::new(tokio::io::stdout()),
+            output: Box::new(tokio::io::stdin()),
+            buf: vec![0; config.max_message_size],
+            progress: FrameProgress::default(),
+        };
+        // Sent bare rather than framed, the producer reads it before any framing starts
+        wrapper.input.write_all(&[READY]).await?;
+        wrapper.input.flush().await?;
+        Ok(wrapper)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        write_frame_async(&mut self.input, data, self.buf.len()).await?;
+        self.input.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<&[u8]> {
+        let len = read_frame_async(&mut self.output, &mut self.buf, &mut self.progress).await?;
+        Ok(&self.buf[..len])
     }
 }
+
+pub type AsyncPipeRunner = AsyncRunner<AsyncPipeWrapper>;
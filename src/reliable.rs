@@ -0,0 +1,489 @@
+// Reliable, ordered messages over datagrams which can be dropped, duplicated or reordered. This
+// only holds the protocol state, the UDP transports feed it what they receive and send what it
+// queues, so the blocking and async versions speak exactly the same protocol.
+//
+// Messages are split into chunks, each carrying the message's id, the chunk's offset and the
+// message's length, so the receiver can put the message back together whatever order its chunks
+// arrive in:
+//
+// | kind: u8 | message id: u32 | offset: u32 | length: u32 | payload |
+//
+// The receiver acks a message once it has all of it. If chunks stop arriving before then it
+// NACKs the first gap, with the length field giving the length of the gap, and the sender resends
+// that range. A sender which hasn't had an ack after RETRANSMIT_TIMEOUT resends the whole message,
+// which covers lost NACKs and acks too. Only one message is in flight each way, a send waits for
+// the previous message to be acked, which also stops a fast sender overrunning the receiver.
+
+use crate::error::{IpcError, Result};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+pub(crate) const HEADER_LEN: usize = 13;
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const NACK: u8 = 2;
+
+/// How long a waiting side sleeps before checking its timers
+pub(crate) const TICK: Duration = Duration::from_millis(5);
+// Chunks stopping for this long before a message is whole means some were lost
+const NACK_DELAY: Duration = Duration::from_millis(10);
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// What a link has had to recover from so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Chunks found missing, which we asked the peer to send again
+    pub lost: usize,
+    /// Chunks we sent again, after a NACK or a retransmit timeout
+    pub retransmitted: usize,
+}
+
+impl Display for LinkStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} chunks lost, {} retransmitted",
+            self.lost, self.retransmitted
+        )
+    }
+}
+
+enum Packet {
+    // A chunk of the message we're sending
+    Chunk { offset: usize },
+    Ack { id: u32 },
+    Nack { id: u32, offset: usize, len: usize },
+}
+
+pub(crate) struct ReliableLink {
+    chunk_size: usize,
+    max_message_size: usize,
+    outbox: VecDeque<Packet>,
+    // Our message in flight
+    tx_id: u32,
+    tx_data: Vec<u8>,
+    tx_pending: bool,
+    tx_sent_at: Instant,
+    // The peer's message we're putting together, or have whole but haven't handed out yet
+    rx_id: u32,
+    rx_data: Vec<u8>,
+    rx_len: usize,
+    rx_chunks: Vec<bool>,
+    // Chunks of this message already counted as lost, so NACKing them again doesn't count them
+    // twice
+    rx_lost: Vec<bool>,
+    rx_missing: usize,
+    rx_started: bool,
+    rx_complete: bool,
+    rx_last_chunk: Instant,
+    stats: LinkStats,
+}
+
+impl ReliableLink {
+    /// `datagram_size` is the most either side puts in a single datagram, header included
+    pub(crate) fn new(datagram_size: usize, max_message_size: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            chunk_size: datagram_size - HEADER_LEN,
+            max_message_size,
+            outbox: VecDeque::new(),
+            tx_id: 0,
+            tx_data: Vec::with_capacity(max_message_size),
+            tx_pending: false,
+            tx_sent_at: now,
+            // Ids start at 1 on both sides, tx_id is bumped before each message
+            rx_id: 1,
+            rx_data: vec![0; max_message_size],
+            rx_len: 0,
+            rx_chunks: Vec::new(),
+            rx_lost: Vec::new(),
+            rx_missing: 0,
+            rx_started: false,
+            rx_complete: false,
+            rx_last_chunk: now,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Whether our last message is still waiting for its ack
+    pub(crate) fn awaiting_ack(&self) -> bool {
+        self.tx_pending
+    }
+
+    /// Whether a whole message is waiting in [`ReliableLink::take_message`]
+    pub(crate) fn has_message(&self) -> bool {
+        self.rx_complete
+    }
+
+    /// Queue `data` to go out. The previous message must have been acked
+    pub(crate) fn start_send(&mut self, data: &[u8]) -> Result<()> {
+        debug_assert!(!self.tx_pending, "Previous message hasn't been acked");
+        if data.len() > self.max_message_size || data.len() > u32::MAX as usize {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: data.len(),
+            });
+        }
+        self.tx_id = self.tx_id.wrapping_add(1);
+        self.tx_data.clear();
+        self.tx_data.extend_from_slice(data);
+        self.tx_pending = true;
+        self.tx_sent_at = Instant::now();
+        self.queue_chunks(0, data.len());
+        Ok(())
+    }
+
+    /// Hand out the message which has arrived whole
+    pub(crate) fn take_message(&mut self) -> &[u8] {
+        debug_assert!(self.rx_complete, "No message to take");
+        self.rx_complete = false;
+        self.rx_started = false;
+        self.rx_id = self.rx_id.wrapping_add(1);
+        &self.rx_data[..self.rx_len]
+    }
+
+    /// Take in a datagram from the peer
+    pub(crate) fn on_datagram(&mut self, datagram: &[u8]) -> Result<()> {
+        let Some((header, payload)) = datagram.split_first_chunk::<HEADER_LEN>() else {
+            return Err(IpcError::protocol("Datagram too short for its header"));
+        };
+        let id = u32::from_le_bytes(header[1..5].try_into().unwrap());
+        let offset = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+        match header[0] {
+            DATA => self.on_chunk(id, offset, len, payload),
+            ACK => {
+                if self.tx_pending && id == self.tx_id {
+                    self.tx_pending = false;
+                }
+                Ok(())
+            }
+            NACK => {
+                if self.tx_pending && id == self.tx_id && offset < self.tx_data.len() {
+                    let end = offset.saturating_add(len).min(self.tx_data.len());
+                    self.stats.retransmitted += self.queue_chunks(offset, end);
+                    self.tx_sent_at = Instant::now();
+                }
+                Ok(())
+            }
+            kind => Err(IpcError::protocol(format!(
+                "Unknown datagram kind {}",
+                kind
+            ))),
+        }
+    }
+
+    /// Resend or NACK whatever has been waiting too long. Call this whenever a wait wakes up
+    pub(crate) fn poll_timers(&mut self) {
+        let now = Instant::now();
+        if self.tx_pending && now.duration_since(self.tx_sent_at) >= RETRANSMIT_TIMEOUT {
+            self.tx_sent_at = now;
+            self.stats.retransmitted += self.queue_chunks(0, self.tx_data.len());
+        }
+        if self.rx_started
+            && !self.rx_complete
+            && now.duration_since(self.rx_last_chunk) >= NACK_DELAY
+        {
+            self.rx_last_chunk = now;
+            let Some(first) = self.rx_chunks.iter().position(|have| !have) else {
+                return;
+            };
+            let missing = self.rx_chunks[first..]
+                .iter()
+                .take_while(|have| !**have)
+                .count();
+            for lost in &mut self.rx_lost[first..first + missing] {
+                if !*lost {
+                    *lost = true;
+                    self.stats.lost += 1;
+                }
+            }
+            let offset = first * self.chunk_size;
+            let end = ((first + missing) * self.chunk_size).min(self.rx_len);
+            self.outbox.push_back(Packet::Nack {
+                id: self.rx_id,
+                offset,
+                len: end - offset,
+            });
+        }
+    }
+
+    /// Write the next datagram we have to send into `buf`, which must hold a whole datagram
+    pub(crate) fn next_datagram<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+        loop {
+            let (kind, id, offset, len, payload) = match self.outbox.pop_front()? {
+                Packet::Chunk { offset } => {
+                    // Acked since it was queued
+                    if !self.tx_pending {
+                        continue;
+                    }
+                    let end = (offset + self.chunk_size).min(self.tx_data.len());
+                    (
+                        DATA,
+                        self.tx_id,
+                        offset,
+                        self.tx_data.len(),
+                        &self.tx_data[offset..end],
+                    )
+                }
+                Packet::Ack { id } => (ACK, id, 0, 0, &[][..]),
+                Packet::Nack { id, offset, len } => (NACK, id, offset, len, &[][..]),
+            };
+            buf[0] = kind;
+            buf[1..5].copy_from_slice(&id.to_le_bytes());
+            buf[5..9].copy_from_slice(&(offset as u32).to_le_bytes());
+            buf[9..13].copy_from_slice(&(len as u32).to_le_bytes());
+            buf[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+            return Some(&buf[..HEADER_LEN + payload.len()]);
+        }
+    }
+
+    // Queue the chunks covering `start..end` of our message, returning how many. An empty message
+    // still goes out as one empty chunk
+    fn queue_chunks(&mut self, start: usize, end: usize) -> usize {
+        let mut offset = start / self.chunk_size * self.chunk_size;
+        let mut count = 0;
+        loop {
+            self.outbox.push_back(Packet::Chunk { offset });
+            count += 1;
+            offset += self.chunk_size;
+            if offset >= end {
+                return count;
+            }
+        }
+    }
+
+    fn on_chunk(&mut self, id: u32, offset: usize, len: usize, payload: &[u8]) -> Result<()> {
+        if id != self.rx_id {
+            // The next message can only come once we've acked this one, but we still have it, so
+            // drop the chunk and let the sender retry. Anything else is a message we've already
+            // handed out, whose ack must have been lost
+            if id != self.rx_id.wrapping_add(1) {
+                self.outbox.push_back(Packet::Ack { id });
+            }
+            return Ok(());
+        }
+        if self.rx_complete {
+            self.outbox.push_back(Packet::Ack { id });
+            return Ok(());
+        }
+        if !self.rx_started {
+            if len > self.max_message_size {
+                return Err(IpcError::SizeMismatch {
+                    expected: self.max_message_size,
+                    actual: len,
+                });
+            }
+            let chunks = len.div_ceil(self.chunk_size).max(1);
+            self.rx_len = len;
+            self.rx_chunks.clear();
+            self.rx_chunks.resize(chunks, false);
+            self.rx_lost.clear();
+            self.rx_lost.resize(chunks, false);
+            self.rx_missing = chunks;
+            self.rx_started = true;
+        }
+        let index = offset / self.chunk_size;
+        if len != self.rx_len
+            || !offset.is_multiple_of(self.chunk_size)
+            || index >= self.rx_chunks.len()
+            || payload.len() != (len - offset).min(self.chunk_size)
+        {
+            return Err(IpcError::protocol(format!(
+                "Chunk at {} doesn't fit message {} of {} bytes",
+                offset, id, self.rx_len
+            )));
+        }
+        self.rx_last_chunk = Instant::now();
+        if self.rx_chunks[index] {
+            return Ok(());
+        }
+        self.rx_chunks[index] = true;
+        self.rx_missing -= 1;
+        self.rx_data[offset..offset + payload.len()].copy_from_slice(payload);
+        if self.rx_missing == 0 {
+            self.rx_complete = true;
+            self.outbox.push_back(Packet::Ack { id });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: usize = 4;
+    const MAX_MESSAGE_SIZE: usize = 64;
+    // Ten chunks
+    const MESSAGE: &[u8; 40] = b"0123456789abcdefghijklmnopqrstuvwxyzABCD";
+
+    fn link() -> ReliableLink {
+        ReliableLink::new(HEADER_LEN + CHUNK_SIZE, MAX_MESSAGE_SIZE)
+    }
+
+    // Everything the link has queued to send
+    fn drain(link: &mut ReliableLink) -> Vec<Vec<u8>> {
+        let mut buf = [0; HEADER_LEN + CHUNK_SIZE];
+        std::iter::from_fn(|| link.next_datagram(&mut buf).map(<[u8]>::to_vec)).collect()
+    }
+
+    // A datagram's kind, id, offset and length
+    fn header(datagram: &[u8]) -> (u8, u32, usize, usize) {
+        let field = |at: usize| u32::from_le_bytes(datagram[at..at + 4].try_into().unwrap());
+        (datagram[0], field(1), field(5) as usize, field(9) as usize)
+    }
+
+    fn deliver(link: &mut ReliableLink, datagrams: &[Vec<u8>]) {
+        for datagram in datagrams {
+            link.on_datagram(datagram).unwrap();
+        }
+    }
+
+    // Make the receiver's NACK timer go off on the next poll
+    fn chunks_stopped(link: &mut ReliableLink) {
+        link.rx_last_chunk = Instant::now() - NACK_DELAY;
+        link.poll_timers();
+    }
+
+    #[test]
+    fn shuffled_and_duplicated_chunks_arrive_whole() {
+        let (mut sender, mut receiver) = (link(), link());
+        sender.start_send(MESSAGE).unwrap();
+        let mut chunks = drain(&mut sender);
+        assert_eq!(chunks.len(), 10);
+        chunks.reverse();
+        chunks.extend_from_within(2..6);
+        chunks.swap(0, 5);
+        deliver(&mut receiver, &chunks);
+
+        assert!(receiver.has_message());
+        assert_eq!(receiver.take_message(), MESSAGE);
+        // Acked once when whole, then again for each duplicate which came after
+        let acks = drain(&mut receiver);
+        assert!(acks.iter().all(|ack| header(ack) == (ACK, 1, 0, 0)));
+        deliver(&mut sender, &acks);
+        assert!(!sender.awaiting_ack());
+        assert_eq!(receiver.stats(), LinkStats::default());
+        assert_eq!(sender.stats(), LinkStats::default());
+    }
+
+    #[test]
+    fn lost_chunks_are_nacked_and_resent() {
+        let (mut sender, mut receiver) = (link(), link());
+        sender.start_send(MESSAGE).unwrap();
+        let chunks = drain(&mut sender);
+        let kept: Vec<_> = chunks
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| ![3, 4, 7].contains(i))
+            .map(|(_, chunk)| chunk.clone())
+            .collect();
+        deliver(&mut receiver, &kept);
+        assert!(!receiver.has_message());
+
+        // Only the first gap is asked for
+        chunks_stopped(&mut receiver);
+        let nacks = drain(&mut receiver);
+        assert_eq!(nacks.len(), 1);
+        assert_eq!(header(&nacks[0]), (NACK, 1, 3 * CHUNK_SIZE, 2 * CHUNK_SIZE));
+        deliver(&mut sender, &nacks);
+        let resent = drain(&mut sender);
+        assert_eq!(resent, chunks[3..5]);
+        deliver(&mut receiver, &resent);
+
+        chunks_stopped(&mut receiver);
+        let nacks = drain(&mut receiver);
+        assert_eq!(header(&nacks[0]), (NACK, 1, 7 * CHUNK_SIZE, CHUNK_SIZE));
+        deliver(&mut sender, &nacks);
+        deliver(&mut receiver, &drain(&mut sender));
+
+        assert_eq!(receiver.take_message(), MESSAGE);
+        assert_eq!(receiver.stats().lost, 3);
+        assert_eq!(sender.stats().retransmitted, 3);
+    }
+
+    #[test]
+    fn a_chunk_nacked_again_is_counted_lost_once() {
+        let (mut sender, mut receiver) = (link(), link());
+        sender.start_send(MESSAGE).unwrap();
+        let mut chunks = drain(&mut sender);
+        chunks.remove(3);
+        deliver(&mut receiver, &chunks);
+
+        for _ in 0..3 {
+            chunks_stopped(&mut receiver);
+        }
+        let nacks = drain(&mut receiver);
+        assert_eq!(nacks.len(), 3);
+        assert!(nacks
+            .iter()
+            .all(|nack| header(nack) == (NACK, 1, 3 * CHUNK_SIZE, CHUNK_SIZE)));
+        assert_eq!(receiver.stats().lost, 1);
+    }
+
+    #[test]
+    fn unacked_message_is_resent_whole() {
+        let mut sender = link();
+        sender.start_send(MESSAGE).unwrap();
+        let chunks = drain(&mut sender);
+        sender.tx_sent_at = Instant::now() - RETRANSMIT_TIMEOUT;
+        sender.poll_timers();
+        assert_eq!(drain(&mut sender), chunks);
+        assert_eq!(sender.stats().retransmitted, chunks.len());
+    }
+
+    #[test]
+    fn chunks_of_a_message_already_taken_are_acked_again() {
+        let (mut sender, mut receiver) = (link(), link());
+        sender.start_send(MESSAGE).unwrap();
+        let chunks = drain(&mut sender);
+        deliver(&mut receiver, &chunks);
+        receiver.take_message();
+        drain(&mut receiver);
+
+        // The ack was lost, so the sender tries again
+        deliver(&mut receiver, &chunks[..1]);
+        assert!(!receiver.has_message());
+        let acks = drain(&mut receiver);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(header(&acks[0]), (ACK, 1, 0, 0));
+    }
+
+    #[test]
+    fn message_ids_wrap() {
+        let (mut sender, mut receiver) = (link(), link());
+        sender.tx_id = u32::MAX - 1;
+        receiver.rx_id = u32::MAX;
+        for (id, message) in [(u32::MAX, &MESSAGE[..8]), (0, &MESSAGE[8..])] {
+            sender.start_send(message).unwrap();
+            let chunks = drain(&mut sender);
+            assert!(chunks.iter().all(|chunk| header(chunk).1 == id));
+            deliver(&mut receiver, &chunks);
+            assert_eq!(receiver.take_message(), message);
+            deliver(&mut sender, &drain(&mut receiver));
+            assert!(!sender.awaiting_ack());
+        }
+    }
+
+    #[test]
+    fn chunk_which_doesnt_fit_the_message_is_a_protocol_error() {
+        let (mut sender, mut receiver) = (link(), link());
+        sender.start_send(MESSAGE).unwrap();
+        let chunks = drain(&mut sender);
+        deliver(&mut receiver, &chunks[..1]);
+        // The same chunk claiming another length for the message
+        let mut bad = chunks[1].clone();
+        bad[9..13].copy_from_slice(&(MESSAGE.len() as u32 + 1).to_le_bytes());
+        assert!(matches!(
+            receiver.on_datagram(&bad),
+            Err(IpcError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,213 @@
+// The layout of the shmem and mmap segments. Both start with a header describing the segment, so
+// a consumer attaching one made by a different build, or sized for different messages, fails with
+// a precise error rather than reading garbage:
+//
+// | header | message |
+//
+// The header holds a magic number, the layout version, the segment's total size, the size of its
+// message slot, the pids of the producer, which created it, and the consumer, the length of the
+// current message, and then the producer's and the consumer's events.
+
+use crate::error::{IpcError, Result};
+use crate::futex::FutexEvent;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+const MAGIC: u32 = u32::from_le_bytes(*b"RIPC");
+/// Bumped whenever the layout changes, so mismatched builds refuse each other's segments
+pub const LAYOUT_VERSION: u32 = 2;
+
+pub(crate) const DATA_START: usize = size_of::<SegmentHeader>();
+
+#[repr(C)]
+pub(crate) struct SegmentHeader {
+    // Written last by the producer, so an attacher never sees a half written header
+    magic: AtomicU32,
+    version: AtomicU32,
+    total_size: AtomicU64,
+    slot_size: AtomicU64,
+    producer_pid: AtomicU32,
+    consumer_pid: AtomicU32,
+    length: AtomicU32,
+    // Signalled by the producer, then the consumer, when a message is ready for the other
+    events: [FutexEvent; 2],
+}
+
+impl SegmentHeader {
+    /// The header of a segment mapped `len` bytes long at `base`
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to `len` bytes which stay mapped for as long as the header is used
+    pub(crate) unsafe fn at<'a>(base: *const u8, len: usize) -> Result<&'a SegmentHeader> {
+        if len < DATA_START {
+            return Err(IpcError::incompatible_segment(format!(
+                "it is {} bytes, too small for the {} byte header",
+                len, DATA_START
+            )));
+        }
+        Ok(&*(base as *const SegmentHeader))
+    }
+
+    /// Fill in the header of a segment we've just created, for messages of up to `slot_size`
+    /// bytes
+    pub(crate) fn init(&self, slot_size: usize) {
+        // A reused file may still hold an old header
+        self.magic.store(0, Ordering::Relaxed);
+        self.version.store(LAYOUT_VERSION, Ordering::Relaxed);
+        self.total_size
+            .store((DATA_START + slot_size) as u64, Ordering::Relaxed);
+        self.slot_size.store(slot_size as u64, Ordering::Relaxed);
+        self.producer_pid
+            .store(std::process::id(), Ordering::Relaxed);
+        self.consumer_pid.store(0, Ordering::Relaxed);
+        self.length.store(0, Ordering::Relaxed);
+        for event in &self.events {
+            event.init();
+        }
+        self.magic.store(MAGIC, Ordering::Release);
+    }
+
+    /// Check a segment we're attaching, mapped `len` bytes long, was laid out by this version for
+    /// messages of up to `slot_size` bytes
+    pub(crate) fn validate(&self, len: usize, slot_size: usize) -> Result<()> {
+        let magic = self.magic.load(Ordering::Acquire);
+        if magic != MAGIC {
+            return Err(IpcError::incompatible_segment(format!(
+                "magic number is {:#010x} rather than {:#010x}, so it isn't set up or isn't ours",
+                magic, MAGIC
+            )));
+        }
+        let version = self.version.load(Ordering::Relaxed);
+        if version != LAYOUT_VERSION {
+            return Err(IpcError::incompatible_segment(format!(
+                "layout version is {} rather than {}",
+                version, LAYOUT_VERSION
+            )));
+        }
+        let their_slot_size = self.slot_size.load(Ordering::Relaxed);
+        if their_slot_size != slot_size as u64 {
+            return Err(IpcError::incompatible_segment(format!(
+                "slot size is {} bytes rather than {}",
+                their_slot_size, slot_size
+            )));
+        }
+        let total_size = self.total_size.load(Ordering::Relaxed);
+        if total_size != (DATA_START + slot_size) as u64 || total_size > len as u64 {
+            return Err(IpcError::incompatible_segment(format!(
+                "total size is {} bytes, for {} bytes mapped and a {} byte slot",
+                total_size, len, slot_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Where the producer or the consumer keeps its pid
+    pub(crate) fn pid(&self, producer: bool) -> &AtomicU32 {
+        if producer {
+            &self.producer_pid
+        } else {
+            &self.consumer_pid
+        }
+    }
+
+    /// The event the producer or the consumer signals
+    pub(crate) fn event(&self, producer: bool) -> &FutexEvent {
+        &self.events[if producer { 0 } else { 1 }]
+    }
+
+    /// Length of the message in the slot, which is only touched between the events
+    pub(crate) fn length(&self) -> &AtomicU32 {
+        &self.length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT_SIZE: usize = 64;
+
+    // A segment on the heap, as words so the header is aligned
+    fn segment(len: usize) -> Vec<u64> {
+        vec![0; len.div_ceil(size_of::<u64>())]
+    }
+
+    fn header(segment: &[u64]) -> &SegmentHeader {
+        unsafe { SegmentHeader::at(segment.as_ptr().cast(), size_of_val(segment)) }.unwrap()
+    }
+
+    fn incompatible(result: Result<()>, what: &str) {
+        assert!(
+            matches!(&result, Err(IpcError::IncompatibleSegment(msg)) if msg.contains(what)),
+            "Got {:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn segment_we_set_up_is_valid() {
+        let memory = segment(DATA_START + SLOT_SIZE);
+        let header = header(&memory);
+        header.init(SLOT_SIZE);
+        header.validate(DATA_START + SLOT_SIZE, SLOT_SIZE).unwrap();
+    }
+
+    #[test]
+    fn segment_too_small_for_the_header_is_incompatible() {
+        let memory = segment(DATA_START);
+        let header = unsafe { SegmentHeader::at(memory.as_ptr().cast(), DATA_START - 1) };
+        assert!(matches!(header, Err(IpcError::IncompatibleSegment(_))));
+    }
+
+    #[test]
+    fn bad_magic_is_incompatible() {
+        let memory = segment(DATA_START + SLOT_SIZE);
+        let header = header(&memory);
+        incompatible(header.validate(DATA_START + SLOT_SIZE, SLOT_SIZE), "magic");
+        header.init(SLOT_SIZE);
+        header.magic.store(!MAGIC, Ordering::Relaxed);
+        incompatible(header.validate(DATA_START + SLOT_SIZE, SLOT_SIZE), "magic");
+    }
+
+    #[test]
+    fn other_layout_version_is_incompatible() {
+        let memory = segment(DATA_START + SLOT_SIZE);
+        let header = header(&memory);
+        header.init(SLOT_SIZE);
+        header.version.store(LAYOUT_VERSION + 1, Ordering::Relaxed);
+        incompatible(
+            header.validate(DATA_START + SLOT_SIZE, SLOT_SIZE),
+            "version",
+        );
+    }
+
+    #[test]
+    fn other_slot_size_is_incompatible() {
+        let memory = segment(DATA_START + 2 * SLOT_SIZE);
+        let header = header(&memory);
+        header.init(SLOT_SIZE);
+        incompatible(
+            header.validate(DATA_START + 2 * SLOT_SIZE, 2 * SLOT_SIZE),
+            "slot size",
+        );
+    }
+
+    #[test]
+    fn total_size_past_the_mapping_is_incompatible() {
+        let memory = segment(DATA_START + SLOT_SIZE);
+        let header = header(&memory);
+        header.init(SLOT_SIZE);
+        incompatible(
+            header.validate(DATA_START + SLOT_SIZE - 1, SLOT_SIZE),
+            "total size",
+        );
+        header
+            .total_size
+            .store((DATA_START + 2 * SLOT_SIZE) as u64, Ordering::Relaxed);
+        incompatible(
+            header.validate(DATA_START + SLOT_SIZE, SLOT_SIZE),
+            "total size",
+        );
+    }
+}
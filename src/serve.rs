@@ -0,0 +1,66 @@
+// Request/response loop for hosting a handler on the consumer side of any transport.
+
+use crate::error::{IpcError, Result};
+use crate::transport::Transport;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Tells a [`serve`] loop to stop. Clones share the same flag, so one can be handed to a signal
+/// handler or another thread while the loop holds the other.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// What a [`serve`] loop got through before it stopped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServeStats {
+    pub requests: usize,
+    pub bytes_received: usize,
+    pub bytes_sent: usize,
+}
+
+/// Receive requests on `transport`, pass each to `handler` and send back what it returns.
+///
+/// Returns once the peer goes away, the peer sends an empty message to ask it to stop, or
+/// `shutdown` is triggered. Shutdown is checked between requests and whenever a receive times
+/// out, so transports which block in `recv` only notice it when the next request arrives.
+pub fn serve<T, F, R>(transport: &mut T, mut handler: F, shutdown: &Shutdown) -> Result<ServeStats>
+where
+    T: Transport,
+    F: FnMut(&[u8]) -> R,
+    R: AsRef<[u8]>,
+{
+    let mut stats = ServeStats::default();
+    while !shutdown.is_triggered() {
+        let response = match transport.recv() {
+            Ok([]) => break,
+            Ok(request) => {
+                stats.bytes_received += request.len();
+                handler(request)
+            }
+            Err(IpcError::PeerGone) => break,
+            // Nothing of the next request had arrived, so it can be waited for again. A stream
+            // cut off partway through one fails with something else, and ends the loop
+            Err(IpcError::Timeout) => continue,
+            Err(e) => return Err(e),
+        };
+        let response = response.as_ref();
+        transport.send(response)?;
+        stats.requests += 1;
+        stats.bytes_sent += response.len();
+    }
+    Ok(stats)
+}
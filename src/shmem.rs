@@ -1,167 +1,228 @@
-use crate::{get_payload, ExecutionResult, KB};
-use raw_sync::events::{BusyEvent, EventImpl, EventInit, EventState};
-use raw_sync::Timeout;
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::futex::{FutexEvent, WaitStrategy};
+use crate::liveness::{PeerWatch, LIVENESS_INTERVAL};
+use crate::segment::{SegmentHeader, DATA_START};
+use crate::transport::{wait_for_consumer, Endpoint, Runner, Transport, TransportConfig};
 use shared_memory::{Shmem, ShmemConf};
-use std::process::{Child, Command};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::process::Child;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// The segment is laid out as described in the segment module: a header, holding the producer's
+// and the consumer's events, and then the message itself
 
 fn shmem_conf(data_size: usize) -> ShmemConf {
-    let shmem = ShmemConf::new().size(data_size);
-    shmem
+    ShmemConf::new().size(data_size)
+}
+
+/// The shared memory segment the producer created, for the consumer to attach
+#[derive(Debug, Clone, clap::Args)]
+pub struct ShmemEndpoint {
+    #[arg(long)]
+    pub os_id: String,
+}
+
+impl Endpoint for ShmemEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec!["--os-id".to_string(), self.os_id.clone()]
+    }
 }
 
 pub struct ShmemWrapper {
     pub shmem: Shmem,
     pub owner: bool,
-    pub our_event: Box<dyn EventImpl>,
-    pub their_event: Box<dyn EventImpl>,
+    pub wait_strategy: WaitStrategy,
     pub data_start: usize,
     pub data_size: usize,
+    // Set once both sides have attached
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
 }
 
 impl ShmemWrapper {
-    pub fn new(handle: Option<String>, data_size: usize) -> ShmemWrapper {
-        let data_size = data_size + 4;
+    pub fn new(
+        handle: Option<String>,
+        max_message_size: usize,
+        wait_strategy: WaitStrategy,
+    ) -> Result<ShmemWrapper> {
+        let data_size = max_message_size + DATA_START;
         let owner = handle.is_none();
         // If we've been given a memory handle, attach it, if not, create one
-        let mut shmem = match handle {
-            None => shmem_conf(data_size).create().unwrap(),
-            Some(h) => shmem_conf(data_size)
-                .os_id(&h)
-                .open()
-                .expect(&format!("Unable to open the shared memory at {}", h)),
+        let shmem = match handle {
+            None => shmem_conf(data_size).create()?,
+            Some(h) => shmem_conf(data_size).os_id(h).open()?,
         };
-        let bytes = unsafe { shmem.as_slice_mut() };
-        // The two events are locks - one for each side. Each side activates the lock while it's
-        // writing, and then unlocks when the data can be read
-        let ((our_event, lock_bytes_ours), (their_event, lock_bytes_theirs)) = unsafe {
-            if owner {
-                (
-                    BusyEvent::new(bytes.get_mut(0).unwrap(), true).unwrap(),
-                    BusyEvent::new(bytes.get_mut(2).unwrap(), true).unwrap(),
-                )
-            } else {
-                (
-                    // If we're not the owner, the events have been created already
-                    BusyEvent::from_existing(bytes.get_mut(2).unwrap()).unwrap(),
-                    BusyEvent::from_existing(bytes.get_mut(0).unwrap()).unwrap(),
-                )
-            }
-        };
-        // Confirm that we've correctly indexed two bytes for each lock
-        assert!(lock_bytes_ours <= 2);
-        assert!(lock_bytes_theirs <= 2);
+        let header = unsafe { SegmentHeader::at(shmem.as_ptr(), shmem.len())? };
+        // If we're not the owner, the header and the events have been set up already
         if owner {
-            our_event.set(EventState::Clear).unwrap();
-            their_event.set(EventState::Clear).unwrap();
+            header.init(max_message_size);
+        } else {
+            header.validate(shmem.len(), max_message_size)?;
         }
-        ShmemWrapper {
+        let wrapper = ShmemWrapper {
             shmem,
             owner,
-            our_event,
-            their_event,
-            data_start: 4,
+            wait_strategy,
+            data_start: DATA_START,
             data_size,
+            peer: None,
+            timeout: None,
+        };
+        if !owner {
+            wrapper
+                .header()
+                .pid(false)
+                .store(std::process::id(), Ordering::Release);
+        }
+        Ok(wrapper)
+    }
+
+    fn header(&self) -> &SegmentHeader {
+        unsafe { &*(self.shmem.as_ptr() as *const SegmentHeader) }
+    }
+
+    // The events are locks - one for each side. Each side activates its lock while it's writing,
+    // and then unlocks when the data can be read
+    fn our_event(&self) -> &FutexEvent {
+        self.header().event(self.owner)
+    }
+
+    fn their_event(&self) -> &FutexEvent {
+        self.header().event(!self.owner)
+    }
+
+    // Start watching the other side, once it has attached and recorded its pid
+    fn watch_peer(&mut self) {
+        let peer_pid = self.header().pid(!self.owner).load(Ordering::Acquire);
+        if peer_pid != 0 {
+            self.peer = Some(PeerWatch::new(peer_pid));
         }
     }
 
     pub fn signal_start(&mut self) {
-        self.our_event.set(EventState::Clear).unwrap()
+        self.our_event().reset();
     }
+
     pub fn signal_finished(&mut self) {
-        self.our_event.set(EventState::Signaled).unwrap()
+        self.our_event().set();
     }
 
-    pub fn write(&mut self, data: &[u8]) {
-        let bytes = unsafe { self.shmem.as_slice_mut() };
-
-        for i in 0..data.len() {
-            bytes[i + self.data_start] = data[i];
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let capacity = self.data_size - self.data_start;
+        if data.len() > capacity {
+            return Err(IpcError::SizeMismatch {
+                expected: capacity,
+                actual: data.len(),
+            });
         }
+        let bytes = unsafe { self.shmem.as_slice_mut() };
+        bytes[self.data_start..self.data_start + data.len()].copy_from_slice(data);
+        self.header()
+            .length()
+            .store(data.len() as u32, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn read(&self) -> &[u8] {
-        unsafe { &self.shmem.as_slice()[self.data_start..self.data_size] }
+    // Only the bytes of the last message written, not the whole segment
+    pub fn read(&self) -> Result<&[u8]> {
+        let bytes = unsafe { self.shmem.as_slice() };
+        let len = self.header().length().load(Ordering::Relaxed) as usize;
+        bytes
+            .get(self.data_start..self.data_start + len)
+            .filter(|_| self.data_start + len <= self.data_size)
+            .ok_or(IpcError::SizeMismatch {
+                expected: self.data_size - self.data_start,
+                actual: len,
+            })
     }
 }
 
-// #[derive(Debug)]
-pub struct ShmemRunner {
-    child_proc: Option<Child>,
-    wrapper: ShmemWrapper,
-    data_size: usize,
-    request_data: Vec<u8>,
-    response_data: Vec<u8>,
-}
+impl Transport for ShmemWrapper {
+    const CONSUMER: &'static str = "shmem";
 
-impl ShmemRunner {
-    pub fn new(start_child: bool, data_size: usize) -> ShmemRunner {
-        let wrapper = ShmemWrapper::new(None, data_size);
-
-        let id = wrapper.shmem.get_os_id();
-        let exe = crate::executable_path("shmem_consumer");
-        let child_proc = if start_child {
-            let res = Some(
-                Command::new(exe)
-                    .args(&[id.to_string(), data_size.to_string()])
-                    .spawn()
-                    .unwrap(),
-            );
-            // Clumsy sleep here but it allows the child proc to spawn without it having to offer
-            // us a ready event
-            sleep(Duration::from_secs(2));
-            res
-        } else {
-            None
-        };
+    const VARIABLE_LENGTH: bool = true;
 
-        let (request_data, response_data) = get_payload(data_size);
+    type Listener = ShmemWrapper;
 
-        ShmemRunner {
-            child_proc,
-            wrapper,
-            data_size,
-            request_data,
-            response_data,
-        }
+    type Endpoint = ShmemEndpoint;
+
+    fn name(config: &TransportConfig) -> String {
+        format!("Shared memory - {} wait", config.wait_strategy)
     }
 
-    pub fn run(&mut self, n: usize, print: bool) {
-        let instant = Instant::now();
-        for _ in 0..n {
-            // Activate our lock in preparation for writing
-            self.wrapper.signal_start();
-            self.wrapper.write(&self.request_data);
-            // Unlock after writing
-            self.wrapper.signal_finished();
-            // Wait for their lock to be released so we can read
-            if self.wrapper.their_event.wait(Timeout::Infinite).is_ok() {
-                let str = self.wrapper.read();
-
-                #[cfg(debug_assertions)]
-                if str.ne(&self.response_data) {
-                    panic!("Sent request didn't get response")
-                }
-            }
-        }
-        let elapsed = instant.elapsed();
-
-        if print {
-            let res = ExecutionResult::new(
-                format!("Shared memory - {}KB", self.data_size / KB),
-                elapsed,
-                n,
-            );
-            res.print_info();
-        }
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        ShmemWrapper::new(None, config.max_message_size, config.wait_strategy)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(ShmemEndpoint {
+            os_id: listener.shmem.get_os_id().to_string(),
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer signals its event once it has attached
+        wait_for_consumer(child, config.ready_timeout, || {
+            Ok(listener.their_event().try_take())
+        })?;
+        let mut transport = listener;
+        transport.watch_peer();
+        Ok(transport)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let mut wrapper = ShmemWrapper::new(
+            Some(endpoint.os_id.clone()),
+            config.max_message_size,
+            config.wait_strategy,
+        )?;
+        wrapper.watch_peer();
+        // Tell the producer we have attached
+        wrapper.signal_finished();
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // The other side has always read the last message before it replies, so a send never waits
+    fn send_timeout(&mut self, data: &[u8], _timeout: Option<Duration>) -> Result<()> {
+        // Activate our lock in preparation for writing
+        self.signal_start();
+        self.write(data)?;
+        // Unlock after writing
+        self.signal_finished();
+        Ok(())
     }
-}
 
-impl Drop for ShmemRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut child) = self.child_proc {
-            child.kill().expect("Unable to kill child process")
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        // Wait for their lock to be released so we can read, giving up if they have gone or we
+        // run out of time
+        loop {
+            let wait = match deadline::remaining(deadline)? {
+                Some(left) => left.min(LIVENESS_INTERVAL),
+                None => LIVENESS_INTERVAL,
+            };
+            if self.their_event().wait(self.wait_strategy, wait) {
+                break;
+            }
+            if let Some(peer) = &self.peer {
+                peer.ensure_alive()?;
+            }
         }
+        self.read()
     }
 }
+
+pub type ShmemRunner = Runner<ShmemWrapper>;
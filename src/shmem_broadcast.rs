@@ -0,0 +1,525 @@
+// One publisher broadcasting to any number of subscriber processes through a ring in a shared
+// memory segment. The segment holds a header, a table of subscriber entries and then the ring:
+//
+// | header | subscriber entries | slots |
+//
+// Each subscriber keeps its own cursor in its entry. The publisher never waits for anyone with
+// the lapping policy, and a subscriber which falls a whole ring behind skips ahead to the
+// oldest message still there, counting what it missed. With backpressure the publisher waits
+// for the slowest subscriber instead, so a subscriber which stops reading stalls the feed. One
+// which has died is evicted: each entry holds its subscriber's pid, and a publisher kept waiting
+// checks them. The header holds the publisher's pid in turn, which waiting subscribers watch.
+//
+// Slots work like a seqlock: the publisher clears a slot's sequence before overwriting it and
+// sets it once the message is in, and readers check it didn't change while they copied.
+
+use crate::error::{IpcError, Result};
+use crate::liveness::{PeerWatch, LIVENESS_INTERVAL};
+use crate::shmem_queue::{backoff, wait_for_worker, Workers};
+use crate::shmem_ring::{CachePadded, CACHE_LINE};
+use crate::transport::wait_for_consumer;
+use crate::{consumer_command, get_payload, ExecutionResult, KB};
+use shared_memory::{Shmem, ShmemConf};
+use std::io::Read;
+use std::mem::size_of;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Name of the `serve` subcommand which runs a broadcast subscriber
+pub const SUBSCRIBER: &str = "broadcast-subscriber";
+const SLOT_HEADER: usize = 2 * size_of::<u64>();
+
+// States of a subscriber entry. A subscriber claims a free entry, sets it up, and only then
+// makes it active, so the publisher never sees an active entry without a cursor
+const FREE: u32 = 0;
+const CLAIMED: u32 = 1;
+const ACTIVE: u32 = 2;
+
+/// What the publisher does about a subscriber which is a whole ring behind
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SlowSubscriberPolicy {
+    /// Overwrite messages it hasn't read yet, it skips ahead once it notices
+    #[default]
+    Lap,
+    /// Wait for it to catch up
+    Backpressure,
+}
+
+#[repr(C)]
+struct BroadcastHeader {
+    capacity: AtomicUsize,
+    slot_size: AtomicUsize,
+    max_subscribers: AtomicUsize,
+    publisher_pid: AtomicU32,
+    // Number of messages published so far
+    head: CachePadded<AtomicU64>,
+}
+
+#[repr(C, align(64))]
+struct SubscriberEntry {
+    state: AtomicU32,
+    pid: AtomicU32,
+    // Next message this subscriber will read
+    cursor: AtomicU64,
+}
+
+#[repr(C)]
+struct Slot {
+    // Position of the message in the slot plus one, or 0 while it's being written
+    sequence: AtomicU64,
+    len: AtomicU64,
+}
+
+struct Segment {
+    shmem: Shmem,
+    capacity: usize,
+    slot_size: usize,
+    max_subscribers: usize,
+    slot_stride: usize,
+}
+
+impl Segment {
+    fn slots_start(max_subscribers: usize) -> usize {
+        size_of::<BroadcastHeader>() + max_subscribers * size_of::<SubscriberEntry>()
+    }
+
+    fn stride(slot_size: usize) -> usize {
+        (SLOT_HEADER + slot_size).next_multiple_of(CACHE_LINE)
+    }
+
+    fn header(&self) -> &BroadcastHeader {
+        unsafe { &*(self.shmem.as_ptr() as *const BroadcastHeader) }
+    }
+
+    fn entry(&self, index: usize) -> &SubscriberEntry {
+        let offset = size_of::<BroadcastHeader>() + index * size_of::<SubscriberEntry>();
+        unsafe { &*(self.shmem.as_ptr().add(offset) as *const SubscriberEntry) }
+    }
+
+    // The slot for `pos` and where its message starts
+    fn slot(&self, pos: u64) -> (&Slot, *mut u8) {
+        let index = (pos % self.capacity as u64) as usize;
+        let offset = Self::slots_start(self.max_subscribers) + index * self.slot_stride;
+        unsafe {
+            let slot = self.shmem.as_ptr().add(offset);
+            (&*(slot as *const Slot), slot.add(SLOT_HEADER))
+        }
+    }
+
+    fn active_entries(&self) -> impl Iterator<Item = &SubscriberEntry> {
+        (0..self.max_subscribers)
+            .map(|i| self.entry(i))
+            .filter(|entry| entry.state.load(Ordering::Acquire) == ACTIVE)
+    }
+}
+
+pub struct Publisher {
+    segment: Segment,
+    policy: SlowSubscriberPolicy,
+    head: u64,
+    // Subscribers found dead and dropped from the ring
+    evicted: usize,
+}
+
+impl Publisher {
+    /// Create a ring of `capacity` messages of up to `slot_size` bytes, with room for
+    /// `max_subscribers` subscribers
+    pub fn create(
+        capacity: usize,
+        slot_size: usize,
+        max_subscribers: usize,
+        policy: SlowSubscriberPolicy,
+    ) -> Result<Self> {
+        if capacity == 0 {
+            return Err(IpcError::protocol("A ring needs at least one slot"));
+        }
+        let slot_stride = Segment::stride(slot_size);
+        let shmem = ShmemConf::new()
+            .size(Segment::slots_start(max_subscribers) + capacity * slot_stride)
+            .create()?;
+        let segment = Segment {
+            shmem,
+            capacity,
+            slot_size,
+            max_subscribers,
+            slot_stride,
+        };
+
+        let header = segment.header();
+        header.capacity.store(capacity, Ordering::Relaxed);
+        header.slot_size.store(slot_size, Ordering::Relaxed);
+        header
+            .max_subscribers
+            .store(max_subscribers, Ordering::Relaxed);
+        header
+            .publisher_pid
+            .store(std::process::id(), Ordering::Relaxed);
+        header.head.0.store(0, Ordering::Relaxed);
+        for i in 0..max_subscribers {
+            segment.entry(i).state.store(FREE, Ordering::Relaxed);
+        }
+        for pos in 0..capacity as u64 {
+            segment.slot(pos).0.sequence.store(0, Ordering::Relaxed);
+        }
+        fence(Ordering::Release);
+
+        Ok(Self {
+            segment,
+            policy,
+            head: 0,
+            evicted: 0,
+        })
+    }
+
+    pub fn os_id(&self) -> &str {
+        self.segment.shmem.get_os_id()
+    }
+
+    /// Number of subscribers currently attached
+    pub fn subscribers(&self) -> usize {
+        self.segment.active_entries().count()
+    }
+
+    /// Subscribers which died while attached and were dropped from the ring
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+
+    // Free the entries of subscribers which died without detaching, so they stop holding up
+    // the ring
+    fn evict_dead(&mut self) {
+        for entry in self.segment.active_entries() {
+            let pid = entry.pid.load(Ordering::Relaxed);
+            if !PeerWatch::new(pid).is_alive()
+                && entry
+                    .state
+                    .compare_exchange(ACTIVE, FREE, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            {
+                self.evicted += 1;
+            }
+        }
+    }
+
+    /// How many messages the slowest subscriber has still to read. With the lapping policy this
+    /// can be more than the ring holds, in which case that subscriber will miss some
+    pub fn lag(&self) -> u64 {
+        self.segment
+            .active_entries()
+            .map(|entry| {
+                self.head
+                    .saturating_sub(entry.cursor.load(Ordering::Acquire))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Publish `data` to every subscriber. With backpressure, returns false without writing if
+    /// the slowest subscriber hasn't made room yet
+    pub fn try_publish(&mut self, data: &[u8]) -> Result<bool> {
+        if data.len() > self.segment.slot_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.segment.slot_size,
+                actual: data.len(),
+            });
+        }
+        if self.policy == SlowSubscriberPolicy::Backpressure
+            && self.lag() >= self.segment.capacity as u64
+        {
+            return Ok(false);
+        }
+
+        let pos = self.head;
+        let (slot, data_ptr) = self.segment.slot(pos);
+        slot.sequence.store(0, Ordering::Relaxed);
+        // Readers must see the slot cleared before any of the new bytes
+        fence(Ordering::Release);
+        slot.len.store(data.len() as u64, Ordering::Relaxed);
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), data_ptr, data.len());
+        }
+        slot.sequence.store(pos + 1, Ordering::Release);
+
+        self.head = pos + 1;
+        self.segment
+            .header()
+            .head
+            .0
+            .store(self.head, Ordering::Release);
+        Ok(true)
+    }
+
+    /// Publish `data` to every subscriber, waiting for room if the policy is backpressure.
+    /// Subscribers which die while we wait are evicted
+    pub fn publish(&mut self, data: &[u8]) -> Result<()> {
+        let mut spins = 0;
+        let mut last_check = Instant::now();
+        while !self.try_publish(data)? {
+            if last_check.elapsed() >= LIVENESS_INTERVAL {
+                last_check = Instant::now();
+                self.evict_dead();
+            }
+            backoff(&mut spins);
+        }
+        Ok(())
+    }
+}
+
+pub struct Subscriber {
+    segment: Segment,
+    publisher: PeerWatch,
+    index: usize,
+    cursor: u64,
+    missed: u64,
+}
+
+impl Subscriber {
+    /// Attach to a publisher's ring. Only messages published from now on will be received
+    pub fn subscribe(os_id: &str) -> Result<Self> {
+        let shmem = ShmemConf::new().os_id(os_id).open()?;
+        if shmem.len() < size_of::<BroadcastHeader>() {
+            return Err(IpcError::SizeMismatch {
+                expected: size_of::<BroadcastHeader>(),
+                actual: shmem.len(),
+            });
+        }
+        let header = unsafe { &*(shmem.as_ptr() as *const BroadcastHeader) };
+        let capacity = header.capacity.load(Ordering::Acquire);
+        let slot_size = header.slot_size.load(Ordering::Acquire);
+        let max_subscribers = header.max_subscribers.load(Ordering::Acquire);
+        let publisher = PeerWatch::new(header.publisher_pid.load(Ordering::Acquire));
+        let slot_stride = Segment::stride(slot_size);
+        let expected = Segment::slots_start(max_subscribers) + capacity * slot_stride;
+        if capacity == 0 || shmem.len() < expected {
+            return Err(IpcError::SizeMismatch {
+                expected,
+                actual: shmem.len(),
+            });
+        }
+        let segment = Segment {
+            shmem,
+            capacity,
+            slot_size,
+            max_subscribers,
+            slot_stride,
+        };
+
+        let index = (0..max_subscribers)
+            .find(|&i| {
+                segment
+                    .entry(i)
+                    .state
+                    .compare_exchange(FREE, CLAIMED, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .ok_or_else(|| IpcError::protocol("No room left for another subscriber"))?;
+        let entry = segment.entry(index);
+        let head = &segment.header().head.0;
+        entry.pid.store(std::process::id(), Ordering::Relaxed);
+        entry
+            .cursor
+            .store(head.load(Ordering::Acquire), Ordering::Relaxed);
+        // Only now can the publisher see us, with our cursor in place
+        entry.state.store(ACTIVE, Ordering::Release);
+        // Until it did, even with backpressure it could publish a whole ring's worth past that
+        // cursor. Starting from where it has got to since means we begin inside the ring
+        fence(Ordering::SeqCst);
+        let cursor = head.load(Ordering::Acquire);
+        entry.cursor.store(cursor, Ordering::Release);
+
+        Ok(Self {
+            segment,
+            publisher,
+            index,
+            cursor,
+            missed: 0,
+        })
+    }
+
+    /// Position of the next message to be read, counting from the first ever published
+    pub fn position(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Messages overwritten before this subscriber got to them
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// Copy the next message into `buf` and return its length, or `None` if there isn't one yet.
+    /// `buf` must be able to hold a whole slot
+    pub fn try_recv(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        if buf.len() < self.segment.slot_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.segment.slot_size,
+                actual: buf.len(),
+            });
+        }
+        loop {
+            let head = self.segment.header().head.0.load(Ordering::Acquire);
+            if self.cursor >= head {
+                return Ok(None);
+            }
+            // We've been lapped, skip to the oldest message still in the ring
+            let oldest = head.saturating_sub(self.segment.capacity as u64);
+            if self.cursor < oldest {
+                self.missed += oldest - self.cursor;
+                self.cursor = oldest;
+            }
+
+            let (slot, data_ptr) = self.segment.slot(self.cursor);
+            if slot.sequence.load(Ordering::Acquire) != self.cursor + 1 {
+                // Being overwritten, so we're about to be lapped again
+                continue;
+            }
+            let len = (slot.len.load(Ordering::Relaxed) as usize).min(self.segment.slot_size);
+            unsafe {
+                std::ptr::copy_nonoverlapping(data_ptr, buf.as_mut_ptr(), len);
+            }
+            fence(Ordering::Acquire);
+            if slot.sequence.load(Ordering::Relaxed) != self.cursor + 1 {
+                continue;
+            }
+
+            self.cursor += 1;
+            self.segment
+                .entry(self.index)
+                .cursor
+                .store(self.cursor, Ordering::Release);
+            return Ok(Some(len));
+        }
+    }
+
+    /// Copy the next message into `buf`, waiting for one to be published. Fails with
+    /// [`IpcError::PeerGone`] if the publisher dies while we wait
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut spins = 0;
+        loop {
+            if let Some(len) = self.try_recv(buf)? {
+                return Ok(len);
+            }
+            self.publisher.check()?;
+            backoff(&mut spins);
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.segment
+            .entry(self.index)
+            .state
+            .store(FREE, Ordering::Release);
+    }
+}
+
+/// What a subscriber is told by [`run_fan_out`]
+#[derive(Debug, Clone, clap::Args)]
+pub struct SubscriberArgs {
+    #[arg(long)]
+    pub os_id: String,
+    /// Messages the publisher will send
+    #[arg(long)]
+    pub count: u64,
+    #[arg(long)]
+    pub data_size: usize,
+}
+
+impl SubscriberArgs {
+    fn to_args(&self) -> Vec<String> {
+        vec![
+            "--os-id".to_string(),
+            self.os_id.clone(),
+            "--count".to_string(),
+            self.count.to_string(),
+            "--data-size".to_string(),
+            self.data_size.to_string(),
+        ]
+    }
+}
+
+/// Publish `n` messages of `data_size` bytes to `subscribers` subscriber processes. Timing starts
+/// once every subscriber has attached and stops when the last one has read the final message
+pub fn run_fan_out(
+    subscribers: usize,
+    n: usize,
+    data_size: usize,
+    capacity: usize,
+    policy: SlowSubscriberPolicy,
+    ready_timeout: Duration,
+    print: bool,
+) -> Result<()> {
+    let mut publisher = Publisher::create(capacity, data_size, subscribers, policy)?;
+
+    let args = SubscriberArgs {
+        os_id: publisher.os_id().to_string(),
+        count: n as u64,
+        data_size,
+    };
+    let mut workers = Workers(Vec::with_capacity(subscribers));
+    for _ in 0..subscribers {
+        let child = consumer_command(SUBSCRIBER)
+            .args(args.to_args())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        workers.0.push(child);
+    }
+
+    wait_for_consumer(None, ready_timeout, || {
+        for child in workers.0.iter_mut() {
+            if child.try_wait()?.is_some() {
+                return Err(IpcError::PeerGone);
+            }
+        }
+        Ok(publisher.subscribers() >= subscribers)
+    })?;
+    let start = Instant::now();
+
+    let (request_data, _) = get_payload(data_size);
+    for _ in 0..n {
+        publisher.publish(&request_data)?;
+    }
+
+    // Each subscriber reports how many messages it missed once it's done
+    let mut missed = 0;
+    for child in workers.0.iter_mut() {
+        let mut output = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_string(&mut output)?;
+        }
+        wait_for_worker(child)?;
+        missed += u64::from_str(output.trim())
+            .map_err(|_| IpcError::protocol(format!("Invalid subscriber report {:?}", output)))?;
+    }
+
+    if print {
+        let name = format!(
+            "Shared memory broadcast - {} subscribers, {:?} - {}KB",
+            subscribers,
+            policy,
+            data_size / KB
+        );
+        ExecutionResult::new(name, start.elapsed(), n).print_info();
+        println!("\t{} messages missed by lapped subscribers", missed);
+    }
+    Ok(())
+}
+
+/// Subscriber run by `ipc serve`, with the args [`run_fan_out`] passed it. Reads until it has
+/// seen the last message and prints how many it missed
+pub fn run_subscriber(args: SubscriberArgs) -> Result<()> {
+    let mut subscriber = Subscriber::subscribe(&args.os_id)?;
+    let (request_data, _) = get_payload(args.data_size);
+    let mut buf = vec![0; args.data_size];
+    while subscriber.position() < args.count {
+        let len = subscriber.recv(&mut buf)?;
+        debug_assert!(
+            buf[..len] == request_data[..],
+            "Didn't receive valid message"
+        );
+    }
+    println!("{}", subscriber.missed());
+    Ok(())
+}
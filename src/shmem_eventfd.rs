@@ -0,0 +1,301 @@
+// Shared memory with blocking wakeups. Messages go through a single slot in a segment laid out as
+// in the segment module, as with the shmem method, but rather than spinning on events in the
+// segment each side sleeps in poll on an eventfd until the other signals it. The events in the
+// segment's header go unused.
+//
+// There is one eventfd for each direction. The producer creates both and the consumer inherits
+// them, their numbers are passed on its command line, so this method needs the producer to start
+// the consumer. Sleeping trades the busy version's latency for a core that's idle between messages.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::liveness::{PeerWatch, LIVENESS_INTERVAL};
+use crate::segment::{SegmentHeader, DATA_START};
+use crate::transport::{wait_for_consumer, Endpoint, Runner, Transport, TransportConfig};
+use shared_memory::{Shmem, ShmemConf};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// The segment and the inherited eventfds, for the consumer to attach
+#[derive(Debug, Clone, clap::Args)]
+pub struct EventFdEndpoint {
+    #[arg(long)]
+    pub os_id: String,
+    /// Signalled by the producer when a request is in the segment
+    #[arg(long)]
+    pub request_fd: RawFd,
+    /// Signalled by the consumer when a response is in the segment, and once it has attached
+    #[arg(long)]
+    pub response_fd: RawFd,
+}
+
+impl Endpoint for EventFdEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec![
+            "--os-id".to_string(),
+            self.os_id.clone(),
+            "--request-fd".to_string(),
+            self.request_fd.to_string(),
+            "--response-fd".to_string(),
+            self.response_fd.to_string(),
+        ]
+    }
+}
+
+struct EventFd(OwnedFd);
+
+impl EventFd {
+    fn new() -> Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    // Take ownership of an eventfd inherited from the producer, after checking that's what `fd`
+    // is, so a wrong number on the command line can't have us poll some other file
+    fn inherited(fd: RawFd) -> Result<Self> {
+        let link = std::fs::read_link(format!("/proc/self/fd/{}", fd))
+            .map_err(|_| IpcError::protocol(format!("No descriptor {} was inherited", fd)))?;
+        if link.as_os_str() != "anon_inode:[eventfd]" {
+            return Err(IpcError::protocol(format!(
+                "Inherited descriptor {} is {:?} rather than an eventfd",
+                fd, link
+            )));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // Inherited without close-on-exec, which we don't want passed on any further
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self(fd))
+    }
+
+    fn signal(&self) -> Result<()> {
+        let one = 1u64.to_ne_bytes();
+        let res = unsafe { libc::write(self.0.as_raw_fd(), one.as_ptr().cast(), one.len()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    // Clear the counter, returning whether it had been signalled
+    fn try_take(&self) -> Result<bool> {
+        let mut count = [0u8; 8];
+        let res = unsafe { libc::read(self.0.as_raw_fd(), count.as_mut_ptr().cast(), count.len()) };
+        if res >= 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+            _ => Err(e.into()),
+        }
+    }
+
+    // Sleep until signalled, waking every LIVENESS_INTERVAL to check on the peer
+    fn wait(&self, deadline: Option<Instant>, peer: Option<&PeerWatch>) -> Result<()> {
+        loop {
+            if self.try_take()? {
+                return Ok(());
+            }
+            let wait = match deadline::remaining(deadline)? {
+                Some(left) => left.min(LIVENESS_INTERVAL),
+                None => LIVENESS_INTERVAL,
+            };
+            match deadline::wait_readable(self.0.as_fd(), deadline::deadline(Some(wait))) {
+                Ok(()) | Err(IpcError::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+            if let Some(peer) = peer {
+                peer.ensure_alive()?;
+            }
+        }
+    }
+}
+
+pub struct ShmemEventFdWrapper {
+    pub shmem: Shmem,
+    pub owner: bool,
+    pub data_size: usize,
+    // We signal ours when a message is ready for the peer, and wait on theirs
+    our_event: EventFd,
+    their_event: EventFd,
+    // Set once both sides have attached
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
+}
+
+impl ShmemEventFdWrapper {
+    /// Create the segment and both eventfds, for the producer
+    pub fn create(max_message_size: usize) -> Result<Self> {
+        let data_size = max_message_size + DATA_START;
+        let shmem = ShmemConf::new().size(data_size).create()?;
+        let header = unsafe { SegmentHeader::at(shmem.as_ptr(), shmem.len())? };
+        header.init(max_message_size);
+        Ok(Self {
+            shmem,
+            owner: true,
+            data_size,
+            our_event: EventFd::new()?,
+            their_event: EventFd::new()?,
+            peer: None,
+            timeout: None,
+        })
+    }
+
+    /// Attach the producer's segment and take the eventfds we inherited, for the consumer
+    pub fn attach(endpoint: &EventFdEndpoint, max_message_size: usize) -> Result<Self> {
+        let shmem = ShmemConf::new().os_id(&endpoint.os_id).open()?;
+        let header = unsafe { SegmentHeader::at(shmem.as_ptr(), shmem.len())? };
+        header.validate(shmem.len(), max_message_size)?;
+        header
+            .pid(false)
+            .store(std::process::id(), Ordering::Release);
+        Ok(Self {
+            shmem,
+            owner: false,
+            data_size: max_message_size + DATA_START,
+            our_event: EventFd::inherited(endpoint.response_fd)?,
+            their_event: EventFd::inherited(endpoint.request_fd)?,
+            peer: None,
+            timeout: None,
+        })
+    }
+
+    fn header(&self) -> &SegmentHeader {
+        unsafe { &*(self.shmem.as_ptr() as *const SegmentHeader) }
+    }
+
+    // Start watching the other side, once it has attached and recorded its pid
+    fn watch_peer(&mut self) {
+        let peer_pid = self.header().pid(!self.owner).load(Ordering::Acquire);
+        if peer_pid != 0 {
+            self.peer = Some(PeerWatch::new(peer_pid));
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let capacity = self.data_size - DATA_START;
+        if data.len() > capacity {
+            return Err(IpcError::SizeMismatch {
+                expected: capacity,
+                actual: data.len(),
+            });
+        }
+        let bytes = unsafe { self.shmem.as_slice_mut() };
+        bytes[DATA_START..DATA_START + data.len()].copy_from_slice(data);
+        self.header()
+            .length()
+            .store(data.len() as u32, Ordering::Release);
+        Ok(())
+    }
+
+    // Only the bytes of the last message written, not the whole segment
+    pub fn read(&self) -> Result<&[u8]> {
+        let len = self.header().length().load(Ordering::Acquire) as usize;
+        let bytes = unsafe { self.shmem.as_slice() };
+        bytes
+            .get(DATA_START..DATA_START + len)
+            .filter(|_| DATA_START + len <= self.data_size)
+            .ok_or(IpcError::SizeMismatch {
+                expected: self.data_size - DATA_START,
+                actual: len,
+            })
+    }
+}
+
+impl Transport for ShmemEventFdWrapper {
+    const CONSUMER: &'static str = "shmem-eventfd";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = ShmemEventFdWrapper;
+
+    type Endpoint = EventFdEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "Shared memory with eventfd wakeups".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        ShmemEventFdWrapper::create(config.max_message_size)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(EventFdEndpoint {
+            os_id: listener.shmem.get_os_id().to_string(),
+            request_fd: listener.our_event.0.as_raw_fd(),
+            response_fd: listener.their_event.0.as_raw_fd(),
+        })
+    }
+
+    // Let the consumer inherit both eventfds, under the same numbers
+    fn prepare_consumer(listener: &Self::Listener, command: &mut Command) -> Result<()> {
+        let fds = [
+            listener.our_event.0.as_raw_fd(),
+            listener.their_event.0.as_raw_fd(),
+        ];
+        unsafe {
+            command.pre_exec(move || {
+                for fd in fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer signals its eventfd once it has attached
+        wait_for_consumer(child, config.ready_timeout, || {
+            listener.their_event.try_take()
+        })?;
+        let mut transport = listener;
+        transport.watch_peer();
+        Ok(transport)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let mut wrapper = ShmemEventFdWrapper::attach(endpoint, config.max_message_size)?;
+        wrapper.watch_peer();
+        // Tell the producer we have attached
+        wrapper.our_event.signal()?;
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // The other side has always read the last message before it replies, so a send never waits
+    fn send_timeout(&mut self, data: &[u8], _timeout: Option<Duration>) -> Result<()> {
+        self.write(data)?;
+        self.our_event.signal()
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        self.their_event
+            .wait(deadline::deadline(timeout), self.peer.as_ref())?;
+        self.read()
+    }
+}
+
+pub type ShmemEventFdRunner = Runner<ShmemEventFdWrapper>;
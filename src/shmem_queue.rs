@@ -0,0 +1,572 @@
+// Bounded multi-producer, multi-consumer queue in a shared memory segment. Any number of
+// processes can attach to the segment by its OS id and push to or pop from it.
+//
+// This is Dmitry Vyukov's bounded MPMC queue. Every slot carries a sequence number which says
+// whose turn it is: a slot at position `pos` can be written when its sequence is `pos`, and read
+// when it is `pos + 1`. Producers and consumers claim positions by moving the shared enqueue and
+// dequeue counters forward. A process which dies between claiming a slot and finishing with it
+// leaves that slot stuck, and the queue stalls once it comes round again.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::liveness::PeerWatch;
+use crate::shmem_ring::{CachePadded, CACHE_LINE};
+use crate::transport::wait_for_consumer;
+use crate::{consumer_command, get_payload, ExecutionResult, KB};
+use shared_memory::{Shmem, ShmemConf};
+use std::hint::spin_loop;
+use std::mem::size_of;
+use std::ops::Range;
+use std::process::Child;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Name of the `serve` subcommand which runs a queue worker
+pub const WORKER: &str = "queue-worker";
+const SLOT_HEADER: usize = 2 * size_of::<u64>();
+// Busy-wait this many times before handing the CPU back, so waiting processes don't starve the
+// ones they are waiting on when there are more processes than cores
+const SPINS_BEFORE_YIELD: u32 = 100;
+// How often the runner checks on its workers while waiting for them to finish. Short, since the
+// wait for the last one is part of the timing
+const WORKER_POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+#[repr(C)]
+struct QueueHeader {
+    capacity: AtomicUsize,
+    slot_size: AtomicUsize,
+    // Number of processes which have attached, not counting the creator
+    attached: AtomicUsize,
+    enqueue: CachePadded<AtomicUsize>,
+    dequeue: CachePadded<AtomicUsize>,
+}
+
+#[repr(C)]
+struct Slot {
+    sequence: AtomicUsize,
+    len: AtomicU64,
+}
+
+pub struct ShmemQueue {
+    pub shmem: Shmem,
+    pub capacity: usize,
+    pub slot_size: usize,
+    slot_stride: usize,
+}
+
+impl ShmemQueue {
+    /// Create a queue of `capacity` messages of up to `slot_size` bytes each
+    pub fn create(capacity: usize, slot_size: usize) -> Result<Self> {
+        if capacity == 0 {
+            return Err(IpcError::protocol("A queue needs at least one slot"));
+        }
+        let slot_stride = Self::stride(slot_size);
+        let shmem = ShmemConf::new()
+            .size(size_of::<QueueHeader>() + capacity * slot_stride)
+            .create()?;
+        let queue = Self {
+            shmem,
+            capacity,
+            slot_size,
+            slot_stride,
+        };
+
+        let header = queue.header();
+        header.capacity.store(capacity, Ordering::Relaxed);
+        header.slot_size.store(slot_size, Ordering::Relaxed);
+        header.attached.store(0, Ordering::Relaxed);
+        header.enqueue.0.store(0, Ordering::Relaxed);
+        header.dequeue.0.store(0, Ordering::Relaxed);
+        for i in 0..capacity {
+            queue.slot(i).0.sequence.store(i, Ordering::Relaxed);
+        }
+        std::sync::atomic::fence(Ordering::Release);
+        Ok(queue)
+    }
+
+    /// Attach to a queue created by another process
+    pub fn attach(os_id: &str) -> Result<Self> {
+        let shmem = ShmemConf::new().os_id(os_id).open()?;
+        if shmem.len() < size_of::<QueueHeader>() {
+            return Err(IpcError::SizeMismatch {
+                expected: size_of::<QueueHeader>(),
+                actual: shmem.len(),
+            });
+        }
+        let header = unsafe { &*(shmem.as_ptr() as *const QueueHeader) };
+        let capacity = header.capacity.load(Ordering::Acquire);
+        let slot_size = header.slot_size.load(Ordering::Acquire);
+        let slot_stride = Self::stride(slot_size);
+        let expected = size_of::<QueueHeader>() + capacity * slot_stride;
+        if capacity == 0 || shmem.len() < expected {
+            return Err(IpcError::SizeMismatch {
+                expected,
+                actual: shmem.len(),
+            });
+        }
+        header.attached.fetch_add(1, Ordering::AcqRel);
+        Ok(Self {
+            shmem,
+            capacity,
+            slot_size,
+            slot_stride,
+        })
+    }
+
+    pub fn os_id(&self) -> &str {
+        self.shmem.get_os_id()
+    }
+
+    /// How many processes have attached so far
+    pub fn attached(&self) -> usize {
+        self.header().attached.load(Ordering::Acquire)
+    }
+
+    fn stride(slot_size: usize) -> usize {
+        (SLOT_HEADER + slot_size).next_multiple_of(CACHE_LINE)
+    }
+
+    fn header(&self) -> &QueueHeader {
+        unsafe { &*(self.shmem.as_ptr() as *const QueueHeader) }
+    }
+
+    // The slot for `pos` and where its message starts
+    fn slot(&self, pos: usize) -> (&Slot, *mut u8) {
+        let offset = size_of::<QueueHeader>() + (pos % self.capacity) * self.slot_stride;
+        unsafe {
+            let slot = self.shmem.as_ptr().add(offset);
+            (&*(slot as *const Slot), slot.add(SLOT_HEADER))
+        }
+    }
+
+    /// Add `data` to the queue. Returns false without writing if the queue is full
+    pub fn try_push(&self, data: &[u8]) -> Result<bool> {
+        if data.len() > self.slot_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.slot_size,
+                actual: data.len(),
+            });
+        }
+        let enqueue = &self.header().enqueue.0;
+        let mut pos = enqueue.load(Ordering::Relaxed);
+        loop {
+            let (slot, data_ptr) = self.slot(pos);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                0 => match enqueue.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        slot.len.store(data.len() as u64, Ordering::Relaxed);
+                        unsafe {
+                            std::ptr::copy_nonoverlapping(data.as_ptr(), data_ptr, data.len());
+                        }
+                        slot.sequence.store(pos + 1, Ordering::Release);
+                        return Ok(true);
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot still holds the message from the last time round
+                diff if diff < 0 => return Ok(false),
+                // Another producer got here first
+                _ => pos = enqueue.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Add `data` to the queue, waiting for space if it's full
+    pub fn push(&self, data: &[u8]) -> Result<()> {
+        let mut spins = 0;
+        while !self.try_push(data)? {
+            backoff(&mut spins);
+        }
+        Ok(())
+    }
+
+    /// Take the oldest message and copy it into `buf`, returning its length, or `None` if the
+    /// queue is empty. `buf` must be able to hold a whole slot
+    pub fn try_pop(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        if buf.len() < self.slot_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.slot_size,
+                actual: buf.len(),
+            });
+        }
+        let dequeue = &self.header().dequeue.0;
+        let mut pos = dequeue.load(Ordering::Relaxed);
+        loop {
+            let (slot, data_ptr) = self.slot(pos);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match dequeue.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let len = (slot.len.load(Ordering::Relaxed) as usize).min(self.slot_size);
+                        unsafe {
+                            std::ptr::copy_nonoverlapping(data_ptr, buf.as_mut_ptr(), len);
+                        }
+                        // Hand the slot to whoever writes this position next time round
+                        slot.sequence.store(pos + self.capacity, Ordering::Release);
+                        return Ok(Some(len));
+                    }
+                    Err(current) => pos = current,
+                },
+                // Nothing has been written here yet
+                diff if diff < 0 => return Ok(None),
+                // Another consumer got here first
+                _ => pos = dequeue.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Take the oldest message, waiting for one if the queue is empty
+    pub fn pop(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut spins = 0;
+        loop {
+            if let Some(len) = self.try_pop(buf)? {
+                return Ok(len);
+            }
+            backoff(&mut spins);
+        }
+    }
+}
+
+pub(crate) fn backoff(spins: &mut u32) {
+    if *spins < SPINS_BEFORE_YIELD {
+        *spins += 1;
+        spin_loop();
+    } else {
+        std::thread::yield_now();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Role {
+    Producer,
+    Consumer,
+}
+
+/// What a queue worker is told by [`run_scaling`]
+#[derive(Debug, Clone, clap::Args)]
+pub struct WorkerArgs {
+    #[arg(long, value_enum)]
+    pub role: Role,
+    #[arg(long)]
+    pub os_id: String,
+    /// Workers to wait for before starting, producers and consumers together
+    #[arg(long)]
+    pub workers: usize,
+    /// Messages each producer pushes
+    #[arg(long)]
+    pub count: usize,
+    #[arg(long)]
+    pub data_size: usize,
+    /// How long to wait for the other workers to attach
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub ready_timeout: Duration,
+}
+
+impl WorkerArgs {
+    fn to_args(&self) -> Vec<String> {
+        let role = match self.role {
+            Role::Producer => "producer",
+            Role::Consumer => "consumer",
+        };
+        vec![
+            "--role".to_string(),
+            role.to_string(),
+            "--os-id".to_string(),
+            self.os_id.clone(),
+            "--workers".to_string(),
+            self.workers.to_string(),
+            "--count".to_string(),
+            self.count.to_string(),
+            "--data-size".to_string(),
+            self.data_size.to_string(),
+            "--ready-timeout".to_string(),
+            humantime::format_duration(self.ready_timeout).to_string(),
+        ]
+    }
+}
+
+// Kills and reaps any workers still running if the run is abandoned
+pub(crate) struct Workers(pub(crate) Vec<Child>);
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        for child in self.0.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+pub(crate) fn wait_for_worker(child: &mut Child) -> Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(IpcError::protocol(format!(
+            "Worker process failed: {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+// Wait for the workers in `which` to finish. Fails as soon as any worker fails, since the others
+// may be stuck waiting on it
+pub(crate) fn wait_for_workers(workers: &mut [Child], which: Range<usize>) -> Result<()> {
+    loop {
+        let mut running = false;
+        for (i, child) in workers.iter_mut().enumerate() {
+            match child.try_wait()? {
+                Some(_) => wait_for_worker(child)?,
+                None => running |= which.contains(&i),
+            }
+        }
+        if !running {
+            return Ok(());
+        }
+        std::thread::sleep(WORKER_POLL_INTERVAL);
+    }
+}
+
+/// Push `n` messages of `data_size` bytes from each of `producers` processes, and pop them all
+/// from `consumers` processes. Timing starts once every worker has attached to the queue
+pub fn run_scaling(
+    producers: usize,
+    consumers: usize,
+    n: usize,
+    data_size: usize,
+    capacity: usize,
+    ready_timeout: Duration,
+    print: bool,
+) -> Result<()> {
+    let queue = ShmemQueue::create(capacity, data_size)?;
+    let expected = producers + consumers;
+
+    let spawn = |role| {
+        let args = WorkerArgs {
+            role,
+            os_id: queue.os_id().to_string(),
+            workers: expected,
+            count: n,
+            data_size,
+            ready_timeout,
+        };
+        consumer_command(WORKER).args(args.to_args()).spawn()
+    };
+    let mut workers = Workers(Vec::with_capacity(expected));
+    for _ in 0..producers {
+        workers.0.push(spawn(Role::Producer)?);
+    }
+    for _ in 0..consumers {
+        workers.0.push(spawn(Role::Consumer)?);
+    }
+
+    wait_for_consumer(None, ready_timeout, || {
+        for child in workers.0.iter_mut() {
+            if child.try_wait()?.is_some() {
+                return Err(IpcError::PeerGone);
+            }
+        }
+        Ok(queue.attached() >= expected)
+    })?;
+    let start = Instant::now();
+
+    wait_for_workers(&mut workers.0, 0..producers)?;
+    // An empty message tells a consumer to stop, it takes them once everything else is popped
+    for _ in 0..consumers {
+        queue.push(&[])?;
+    }
+    wait_for_workers(&mut workers.0, producers..expected)?;
+
+    if print {
+        let name = format!(
+            "Shared memory queue - {} producers, {} consumers - {}KB",
+            producers,
+            consumers,
+            data_size / KB
+        );
+        ExecutionResult::new(name, start.elapsed(), producers * n).print_info();
+    }
+    Ok(())
+}
+
+/// Queue worker run by `ipc serve`, with the args [`run_scaling`] passed it
+pub fn run_worker(args: WorkerArgs) -> Result<()> {
+    let queue = ShmemQueue::attach(&args.os_id)?;
+    // Don't start until everyone is here, so the first workers don't have the queue to
+    // themselves. Spin rather than sleep, the runner starts timing as soon as the last attaches
+    let deadline = deadline::deadline(Some(args.ready_timeout));
+    let mut runner = PeerWatch::new(std::os::unix::process::parent_id());
+    let mut spins = 0;
+    while queue.attached() < args.workers {
+        deadline::check(deadline)?;
+        runner.check()?;
+        backoff(&mut spins);
+    }
+
+    // Waits on the queue check on the runner too, so workers don't spin forever if it's killed
+    let (request_data, _) = get_payload(args.data_size);
+    match args.role {
+        Role::Producer => {
+            for _ in 0..args.count {
+                let mut spins = 0;
+                while !queue.try_push(&request_data)? {
+                    runner.check()?;
+                    backoff(&mut spins);
+                }
+            }
+        }
+        Role::Consumer => {
+            let mut buf = vec![0; queue.slot_size];
+            loop {
+                let mut spins = 0;
+                let len = loop {
+                    if let Some(len) = queue.try_pop(&mut buf)? {
+                        break len;
+                    }
+                    runner.check()?;
+                    backoff(&mut spins);
+                };
+                if len == 0 {
+                    break;
+                }
+                debug_assert!(
+                    buf[..len] == request_data[..],
+                    "Didn't receive valid message"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const CAPACITY: usize = 4;
+    const SLOT_SIZE: usize = 8;
+
+    fn sequence(queue: &ShmemQueue, pos: usize) -> usize {
+        queue.slot(pos).0.sequence.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn sequences_hand_slots_round_between_producers_and_consumers() -> Result<()> {
+        let queue = ShmemQueue::create(CAPACITY, SLOT_SIZE)?;
+        let mut buf = [0; SLOT_SIZE];
+        assert_eq!(queue.try_pop(&mut buf)?, None);
+
+        // Several times round, so positions and sequences run past the capacity
+        for round in 0..3 {
+            let start = round * CAPACITY;
+            for pos in start..start + CAPACITY {
+                assert_eq!(
+                    sequence(&queue, pos),
+                    pos,
+                    "Slot {} isn't free to write",
+                    pos
+                );
+                assert!(queue.try_push(&pos.to_le_bytes())?);
+                assert_eq!(
+                    sequence(&queue, pos),
+                    pos + 1,
+                    "Slot {} isn't ready to read",
+                    pos
+                );
+            }
+            assert!(!queue.try_push(b"full")?);
+
+            for pos in start..start + CAPACITY {
+                assert_eq!(queue.try_pop(&mut buf)?, Some(SLOT_SIZE));
+                assert_eq!(usize::from_le_bytes(buf), pos);
+                assert_eq!(sequence(&queue, pos), pos + CAPACITY);
+            }
+            assert_eq!(queue.try_pop(&mut buf)?, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_sizes_are_refused() -> Result<()> {
+        let queue = ShmemQueue::create(CAPACITY, SLOT_SIZE)?;
+        let pushed = queue.try_push(&[0; SLOT_SIZE + 1]);
+        assert!(matches!(pushed, Err(IpcError::SizeMismatch { .. })));
+        let popped = queue.try_pop(&mut [0; SLOT_SIZE - 1]);
+        assert!(matches!(popped, Err(IpcError::SizeMismatch { .. })));
+        assert!(ShmemQueue::create(0, SLOT_SIZE).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn every_message_is_popped_once() -> Result<()> {
+        const PRODUCERS: usize = 3;
+        const CONSUMERS: usize = 3;
+        const COUNT: usize = 2000;
+        let queue = ShmemQueue::create(CAPACITY, SLOT_SIZE)?;
+        let os_id = queue.os_id().to_string();
+
+        let popped = std::thread::scope(|scope| -> Result<Vec<usize>> {
+            let producers: Vec<_> = (0..PRODUCERS)
+                .map(|producer| {
+                    let os_id = &os_id;
+                    scope.spawn(move || -> Result<()> {
+                        let queue = ShmemQueue::attach(os_id)?;
+                        for i in 0..COUNT {
+                            // Leave 0 free to tell the consumers to stop
+                            queue.push(&(1 + producer * COUNT + i).to_le_bytes())?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            let consumers: Vec<_> = (0..CONSUMERS)
+                .map(|_| {
+                    let os_id = &os_id;
+                    scope.spawn(move || -> Result<Vec<usize>> {
+                        let queue = ShmemQueue::attach(os_id)?;
+                        let mut buf = [0; SLOT_SIZE];
+                        let mut popped = Vec::new();
+                        loop {
+                            queue.pop(&mut buf)?;
+                            match usize::from_le_bytes(buf) {
+                                0 => return Ok(popped),
+                                message => popped.push(message),
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            for producer in producers {
+                producer.join().unwrap()?;
+            }
+            for _ in 0..CONSUMERS {
+                queue.push(&0usize.to_le_bytes())?;
+            }
+            let mut popped = Vec::new();
+            for consumer in consumers {
+                popped.extend(consumer.join().unwrap()?);
+            }
+            Ok(popped)
+        })?;
+
+        assert_eq!(popped.len(), PRODUCERS * COUNT);
+        let unique: HashSet<_> = popped.iter().collect();
+        assert_eq!(unique.len(), popped.len(), "A message was popped twice");
+        assert!(popped
+            .iter()
+            .all(|&message| (1..=PRODUCERS * COUNT).contains(&message)));
+        Ok(())
+    }
+}
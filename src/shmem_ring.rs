@@ -0,0 +1,361 @@
+// Single producer, single consumer ring buffers in a shared memory segment. The segment holds a
+// small header and then two rings, one for each direction:
+//
+// | header | producer -> consumer ring | consumer -> producer ring |
+//
+// A ring starts with its head and tail indices, each on its own cache line so the two sides
+// don't keep stealing the line from each other, followed by its slots. Each slot holds the
+// length of the message and then the message itself.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::liveness::PeerWatch;
+use crate::shmem::ShmemEndpoint;
+use crate::transport::{wait_for_consumer, Runner, Transport, TransportConfig};
+use shared_memory::{Shmem, ShmemConf};
+use std::hint::spin_loop;
+use std::mem::size_of;
+use std::process::Child;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub(crate) const CACHE_LINE: usize = 64;
+const SLOT_HEADER: usize = size_of::<u64>();
+
+#[repr(C, align(64))]
+pub(crate) struct CachePadded<T>(pub(crate) T);
+
+#[repr(C, align(64))]
+struct SegmentHeader {
+    slot_count: AtomicUsize,
+    slot_size: AtomicUsize,
+    // Set by the consumer once it has attached
+    attached: AtomicU32,
+    producer_pid: AtomicU32,
+    consumer_pid: AtomicU32,
+}
+
+#[repr(C)]
+struct RingIndices {
+    // Next slot to write, only moved by the sending side
+    head: CachePadded<AtomicUsize>,
+    // Next slot to read, only moved by the receiving side
+    tail: CachePadded<AtomicUsize>,
+}
+
+/// One direction of the channel. Only one process may push and only one may read.
+pub struct SpscRing {
+    indices: *const RingIndices,
+    slots: *mut u8,
+    slot_count: usize,
+    slot_size: usize,
+    slot_stride: usize,
+}
+
+impl SpscRing {
+    /// Bytes taken up by a ring of `slot_count` slots of `slot_size` bytes each
+    pub fn footprint(slot_count: usize, slot_size: usize) -> usize {
+        size_of::<RingIndices>() + slot_count * Self::stride(slot_size)
+    }
+
+    fn stride(slot_size: usize) -> usize {
+        (SLOT_HEADER + slot_size).next_multiple_of(CACHE_LINE)
+    }
+
+    /// # Safety
+    ///
+    /// `base` must be aligned to a cache line and point to at least
+    /// [`SpscRing::footprint`] bytes which stay mapped for as long as the ring is used
+    pub unsafe fn from_raw(base: *mut u8, slot_count: usize, slot_size: usize) -> Self {
+        Self {
+            indices: base as *const RingIndices,
+            slots: base.add(size_of::<RingIndices>()),
+            slot_count,
+            slot_size,
+            slot_stride: Self::stride(slot_size),
+        }
+    }
+
+    fn indices(&self) -> &RingIndices {
+        unsafe { &*self.indices }
+    }
+
+    fn slot(&self, index: usize) -> *mut u8 {
+        unsafe { self.slots.add((index % self.slot_count) * self.slot_stride) }
+    }
+
+    pub fn reset(&self) {
+        self.indices().head.0.store(0, Ordering::Relaxed);
+        self.indices().tail.0.store(0, Ordering::Release);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let indices = self.indices();
+        indices.head.0.load(Ordering::Acquire) == indices.tail.0.load(Ordering::Relaxed)
+    }
+
+    /// Copy `data` into the next free slot. Returns false without writing if the ring is full
+    pub fn try_push(&self, data: &[u8]) -> Result<bool> {
+        if data.len() > self.slot_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.slot_size,
+                actual: data.len(),
+            });
+        }
+        let indices = self.indices();
+        let head = indices.head.0.load(Ordering::Relaxed);
+        let tail = indices.tail.0.load(Ordering::Acquire);
+        if head - tail == self.slot_count {
+            return Ok(false);
+        }
+        let slot = self.slot(head);
+        unsafe {
+            (slot as *mut u64).write(data.len() as u64);
+            std::ptr::copy_nonoverlapping(data.as_ptr(), slot.add(SLOT_HEADER), data.len());
+        }
+        // Publish the slot only once it's filled in
+        indices.head.0.store(head + 1, Ordering::Release);
+        Ok(true)
+    }
+
+    /// The oldest unread message, if there is one. It keeps its slot until [`SpscRing::release`]
+    pub fn peek(&self) -> Result<Option<&[u8]>> {
+        let indices = self.indices();
+        let tail = indices.tail.0.load(Ordering::Relaxed);
+        if indices.head.0.load(Ordering::Acquire) == tail {
+            return Ok(None);
+        }
+        let slot = self.slot(tail);
+        let len = unsafe { (slot as *const u64).read() } as usize;
+        if len > self.slot_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.slot_size,
+                actual: len,
+            });
+        }
+        Ok(Some(unsafe {
+            std::slice::from_raw_parts(slot.add(SLOT_HEADER), len)
+        }))
+    }
+
+    /// Hand the oldest message's slot back to the sender
+    pub fn release(&self) {
+        let tail = self.indices().tail.0.load(Ordering::Relaxed);
+        self.indices().tail.0.store(tail + 1, Ordering::Release);
+    }
+}
+
+pub struct ShmemRingWrapper {
+    pub shmem: Shmem,
+    pub owner: bool,
+    pub slot_count: usize,
+    tx: SpscRing,
+    rx: SpscRing,
+    // The message returned by the last recv stays in its slot until we next send or receive
+    holding: bool,
+    // Set once both sides have attached
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
+}
+
+impl ShmemRingWrapper {
+    pub fn new(
+        handle: Option<String>,
+        slot_count: usize,
+        max_message_size: usize,
+    ) -> Result<ShmemRingWrapper> {
+        let owner = handle.is_none();
+        let shmem = match handle {
+            None => {
+                if slot_count == 0 {
+                    return Err(IpcError::protocol("A ring needs at least one slot"));
+                }
+                let size = size_of::<SegmentHeader>()
+                    + 2 * SpscRing::footprint(slot_count, max_message_size);
+                ShmemConf::new().size(size).create()?
+            }
+            Some(h) => ShmemConf::new().os_id(h).open()?,
+        };
+        if shmem.len() < size_of::<SegmentHeader>() {
+            return Err(IpcError::SizeMismatch {
+                expected: size_of::<SegmentHeader>(),
+                actual: shmem.len(),
+            });
+        }
+
+        let base = shmem.as_ptr();
+        let header = unsafe { &*(base as *const SegmentHeader) };
+        // The consumer takes the layout from the header the producer filled in
+        let (slot_count, slot_size) = if owner {
+            header.slot_count.store(slot_count, Ordering::Relaxed);
+            header.slot_size.store(max_message_size, Ordering::Relaxed);
+            header.consumer_pid.store(0, Ordering::Relaxed);
+            header
+                .producer_pid
+                .store(std::process::id(), Ordering::Relaxed);
+            header.attached.store(0, Ordering::Release);
+            (slot_count, max_message_size)
+        } else {
+            (
+                header.slot_count.load(Ordering::Acquire),
+                header.slot_size.load(Ordering::Acquire),
+            )
+        };
+        let ring_size = SpscRing::footprint(slot_count, slot_size);
+        let expected = size_of::<SegmentHeader>() + 2 * ring_size;
+        if slot_count == 0 || shmem.len() < expected {
+            return Err(IpcError::SizeMismatch {
+                expected,
+                actual: shmem.len(),
+            });
+        }
+        if slot_size < max_message_size {
+            return Err(IpcError::SizeMismatch {
+                expected: max_message_size,
+                actual: slot_size,
+            });
+        }
+
+        let (forward, backward) = unsafe {
+            let rings = base.add(size_of::<SegmentHeader>());
+            (
+                SpscRing::from_raw(rings, slot_count, slot_size),
+                SpscRing::from_raw(rings.add(ring_size), slot_count, slot_size),
+            )
+        };
+        let (tx, rx) = if owner {
+            forward.reset();
+            backward.reset();
+            (forward, backward)
+        } else {
+            (backward, forward)
+        };
+
+        Ok(ShmemRingWrapper {
+            shmem,
+            owner,
+            slot_count,
+            tx,
+            rx,
+            holding: false,
+            peer: None,
+            timeout: None,
+        })
+    }
+
+    fn header(&self) -> &SegmentHeader {
+        unsafe { &*(self.shmem.as_ptr() as *const SegmentHeader) }
+    }
+
+    // Fails once the other side has exited, so a spin on the rings can't go on forever
+    fn check_peer(&mut self) -> Result<()> {
+        match &mut self.peer {
+            Some(peer) => peer.check(),
+            None => Ok(()),
+        }
+    }
+
+    fn release_held(&mut self) {
+        if self.holding {
+            self.rx.release();
+            self.holding = false;
+        }
+    }
+}
+
+impl Transport for ShmemRingWrapper {
+    const CONSUMER: &'static str = "shmem-ring";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = ShmemRingWrapper;
+
+    type Endpoint = ShmemEndpoint;
+
+    fn name(config: &TransportConfig) -> String {
+        format!("Shared memory ring - {} slots", config.ring_slots)
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        ShmemRingWrapper::new(None, config.ring_slots, config.max_message_size)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(ShmemEndpoint {
+            os_id: listener.shmem.get_os_id().to_string(),
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        wait_for_consumer(child, config.ready_timeout, || {
+            Ok(listener.header().attached.load(Ordering::Acquire) != 0)
+        })?;
+        let mut transport = listener;
+        let consumer_pid = transport.header().consumer_pid.load(Ordering::Relaxed);
+        transport.peer = Some(PeerWatch::new(consumer_pid));
+        Ok(transport)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let mut wrapper =
+            ShmemRingWrapper::new(Some(endpoint.os_id.clone()), 0, config.max_message_size)?;
+        let header = wrapper.header();
+        header
+            .consumer_pid
+            .store(std::process::id(), Ordering::Relaxed);
+        header.attached.store(1, Ordering::Release);
+        let producer_pid = header.producer_pid.load(Ordering::Relaxed);
+        wrapper.peer = Some(PeerWatch::new(producer_pid));
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let deadline = deadline::deadline(timeout);
+        self.release_held();
+        while !self.tx.try_push(data)? {
+            self.check_peer()?;
+            deadline::check(deadline)?;
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        // Only one try, a full ring means the consumer has stopped reading and will be killed
+        self.release_held();
+        self.tx.try_push(&[])?;
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        self.release_held();
+        while self.rx.is_empty() {
+            self.check_peer()?;
+            deadline::check(deadline)?;
+            spin_loop();
+        }
+        self.holding = true;
+        self.rx
+            .peek()?
+            .ok_or_else(|| IpcError::protocol("Ring emptied under the reader"))
+    }
+
+    fn max_in_flight(&self) -> usize {
+        self.slot_count
+    }
+}
+
+pub type ShmemRingRunner = Runner<ShmemRingWrapper>;
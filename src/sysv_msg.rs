@@ -0,0 +1,368 @@
+// A System V message queue, for comparing against software still built on msgget/msgsnd. One
+// queue carries both directions, requests as one message type and responses as another. The
+// kernel caps messages at /proc/sys/kernel/msgmax, 8KB by default, so bigger messages are split
+// over several queue messages as in the mqueue module, the first starting with the length:
+//
+// | message length: u32 | payload... |  | payload... |  ...
+//
+// msgsnd and msgrcv can't be given a timeout, and a call blocked in them doesn't notice the other
+// side dying, so each side polls with IPC_NOWAIT and checks its deadline and the other side's pid
+// as it goes. It yields the core between tries at first, then sleeps for longer and longer, so an
+// idle queue doesn't keep a core busy. The queue's key comes from a hash of the run's name, and
+// the producer removes it with IPC_RMID when it's done, or when it's dropped if it never got as
+// far as closing.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::liveness::PeerWatch;
+use crate::transport::{
+    wait_for_consumer, with_free_key, Endpoint, Runner, Transport, TransportConfig,
+};
+use std::io;
+use std::mem::size_of;
+use std::process::Child;
+use std::time::{Duration, Instant};
+
+const HEADER_LEN: usize = 4;
+// Every queue message starts with its type, a C long
+const TYPE_LEN: usize = size_of::<libc::c_long>();
+const REQUEST: libc::c_long = 1;
+const RESPONSE: libc::c_long = 2;
+// Only the owner can use the queue
+const QUEUE_MODE: libc::c_int = 0o600;
+// A wait on the queue yields this many times before it starts sleeping between tries
+const YIELDS_BEFORE_SLEEP: u32 = 1000;
+// The sleeps start short and double up to the longest
+const FIRST_SLEEP: Duration = Duration::from_micros(10);
+const LONGEST_SLEEP: Duration = Duration::from_millis(1);
+// Used if the limits can't be read, they're the kernel's defaults
+const DEFAULT_MSGMAX: usize = 8192;
+const DEFAULT_MSGMNB: usize = 16384;
+
+/// The queue's key, and the producer's pid for the consumer to watch it
+#[derive(Debug, Clone, clap::Args)]
+pub struct SysvMsgEndpoint {
+    // Keys can be negative
+    #[arg(long, allow_negative_numbers = true)]
+    pub key: libc::key_t,
+    #[arg(long)]
+    pub producer_pid: u32,
+}
+
+impl Endpoint for SysvMsgEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec![
+            "--key".to_string(),
+            self.key.to_string(),
+            "--producer-pid".to_string(),
+            self.producer_pid.to_string(),
+        ]
+    }
+}
+
+// One of the system's message queue limits
+fn queue_limit(name: &str, default: usize) -> usize {
+    std::fs::read_to_string(format!("/proc/sys/kernel/{}", name))
+        .ok()
+        .and_then(|limit| limit.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+// Most payload bytes one queue message carries, header included. Both sides work it out the same
+// way, from the same limits
+fn chunk_size(max_message_size: usize) -> usize {
+    (HEADER_LEN + max_message_size)
+        .min(queue_limit("msgmax", DEFAULT_MSGMAX))
+        .min(queue_limit("msgmnb", DEFAULT_MSGMNB))
+}
+
+pub struct SysvMsgWrapper {
+    pub key: libc::key_t,
+    pub owner: bool,
+    // Set once the producer has removed the queue, so dropping it afterwards can't remove one
+    // which has since been given the same id
+    removed: bool,
+    id: libc::c_int,
+    our_type: libc::c_long,
+    their_type: libc::c_long,
+    max_message_size: usize,
+    chunk_size: usize,
+    // A queue message on its way out or in, its type followed by up to `chunk_size` bytes
+    chunk: Vec<u8>,
+    // The whole message received last
+    buf: Vec<u8>,
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
+}
+
+impl SysvMsgWrapper {
+    /// Create the queue under a free key for the endpoint `name`, for the producer
+    pub fn create(name: &str, max_message_size: usize) -> Result<Self> {
+        with_free_key(name, |key| {
+            let id = unsafe { libc::msgget(key, libc::IPC_CREAT | libc::IPC_EXCL | QUEUE_MODE) };
+            if id < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(Self::new(key, true, id, max_message_size))
+        })
+    }
+
+    /// Open the producer's queue, for the consumer
+    pub fn open(key: libc::key_t, max_message_size: usize) -> Result<Self> {
+        let id = unsafe { libc::msgget(key, 0) };
+        if id < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self::new(key, false, id, max_message_size))
+    }
+
+    fn new(key: libc::key_t, owner: bool, id: libc::c_int, max_message_size: usize) -> Self {
+        let chunk_size = chunk_size(max_message_size);
+        let (our_type, their_type) = if owner {
+            (REQUEST, RESPONSE)
+        } else {
+            (RESPONSE, REQUEST)
+        };
+        Self {
+            key,
+            owner,
+            removed: false,
+            id,
+            our_type,
+            their_type,
+            max_message_size,
+            chunk_size,
+            chunk: vec![0; TYPE_LEN + chunk_size],
+            buf: vec![0; max_message_size],
+            peer: None,
+            timeout: None,
+        }
+    }
+
+    // Send the first `len` bytes after the type in `chunk`, None if the queue is full
+    fn try_send_chunk(&mut self, len: usize) -> Result<Option<()>> {
+        self.chunk[..TYPE_LEN].copy_from_slice(&self.our_type.to_ne_bytes());
+        let res =
+            unsafe { libc::msgsnd(self.id, self.chunk.as_ptr().cast(), len, libc::IPC_NOWAIT) };
+        if res == 0 {
+            return Ok(Some(()));
+        }
+        queue_error(self.id, io::Error::last_os_error(), libc::EAGAIN)
+    }
+
+    // Receive a queue message into `chunk`, returning its length, None if there isn't one
+    fn try_recv_chunk(&mut self) -> Result<Option<usize>> {
+        let res = unsafe {
+            libc::msgrcv(
+                self.id,
+                self.chunk.as_mut_ptr().cast(),
+                self.chunk_size,
+                self.their_type,
+                libc::IPC_NOWAIT,
+            )
+        };
+        if res >= 0 {
+            return Ok(Some(res as usize));
+        }
+        queue_error(self.id, io::Error::last_os_error(), libc::ENOMSG)
+    }
+
+    fn send_chunk(&mut self, len: usize, deadline: Option<Instant>) -> Result<()> {
+        let mut tries = 0;
+        loop {
+            if self.try_send_chunk(len)?.is_some() {
+                return Ok(());
+            }
+            self.wait(deadline, &mut tries)?;
+        }
+    }
+
+    fn recv_chunk(&mut self, deadline: Option<Instant>) -> Result<usize> {
+        let mut tries = 0;
+        loop {
+            if let Some(len) = self.try_recv_chunk()? {
+                return Ok(len);
+            }
+            self.wait(deadline, &mut tries)?;
+        }
+    }
+
+    // Give up the core before trying the queue again, for longer the more `tries` there have been
+    fn wait(&mut self, deadline: Option<Instant>, tries: &mut u32) -> Result<()> {
+        let left = deadline::remaining(deadline)?;
+        *tries = tries.saturating_add(1);
+        if *tries <= YIELDS_BEFORE_SLEEP {
+            if let Some(peer) = self.peer.as_mut() {
+                peer.check()?;
+            }
+            std::thread::yield_now();
+            return Ok(());
+        }
+        // Next to a sleep, checking the peer every time costs nothing
+        if let Some(peer) = &self.peer {
+            peer.ensure_alive()?;
+        }
+        let doublings = (*tries - YIELDS_BEFORE_SLEEP - 1).min(8);
+        let sleep = (FIRST_SLEEP * 2u32.pow(doublings)).min(LONGEST_SLEEP);
+        std::thread::sleep(left.map_or(sleep, |left| left.min(sleep)));
+        Ok(())
+    }
+}
+
+// Whether the queue `id` still exists
+fn queue_exists(id: libc::c_int) -> bool {
+    let mut stat: libc::msqid_ds = unsafe { std::mem::zeroed() };
+    unsafe { libc::msgctl(id, libc::IPC_STAT, &mut stat) == 0 }
+}
+
+// An error from a call on the queue `id` which didn't go through. `again` is what the call fails
+// with when it would have blocked
+fn queue_error<T>(id: libc::c_int, e: io::Error, again: libc::c_int) -> Result<Option<T>> {
+    match e.raw_os_error() {
+        Some(errno) if errno == again => Ok(None),
+        Some(libc::EINTR) => Ok(None),
+        // The producer removed the queue, so it has finished with us. EINVAL is also what a bad
+        // size or type gets, so it only means that once the queue is gone
+        Some(libc::EIDRM) => Err(IpcError::PeerGone),
+        Some(libc::EINVAL) if !queue_exists(id) => Err(IpcError::PeerGone),
+        _ => Err(e.into()),
+    }
+}
+
+impl Transport for SysvMsgWrapper {
+    const CONSUMER: &'static str = "sysv-msg";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = SysvMsgWrapper;
+
+    type Endpoint = SysvMsgEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "System V message queue".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        SysvMsgWrapper::create(&config.resolve_endpoint_name(), config.max_message_size)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(SysvMsgEndpoint {
+            key: listener.key,
+            producer_pid: std::process::id(),
+        })
+    }
+
+    fn accept(
+        mut listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let consumer_pid = child.as_ref().map(|child| child.id());
+        // The consumer sends a bare queue message once it has opened the queue
+        let ready = wait_for_consumer(child, config.ready_timeout, || {
+            match listener.try_recv_chunk()? {
+                None => Ok(false),
+                Some(0) => Ok(true),
+                Some(_) => Err(IpcError::protocol("Expected the consumer's ready signal")),
+            }
+        });
+        if let Err(e) = ready {
+            listener.close();
+            return Err(e);
+        }
+        listener.peer = consumer_pid.map(PeerWatch::new);
+        Ok(listener)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let mut wrapper = SysvMsgWrapper::open(endpoint.key, config.max_message_size)?;
+        wrapper.send_chunk(0, deadline::deadline(Some(config.ready_timeout)))?;
+        wrapper.peer = Some(PeerWatch::new(endpoint.producer_pid));
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        if data.len() > self.max_message_size || data.len() > u32::MAX as usize {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: data.len(),
+            });
+        }
+        let deadline = deadline::deadline(timeout);
+        // The first queue message carries the length, and as much payload as fits after it
+        let first = data.len().min(self.chunk_size - HEADER_LEN);
+        self.chunk[TYPE_LEN..TYPE_LEN + HEADER_LEN]
+            .copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.chunk[TYPE_LEN + HEADER_LEN..TYPE_LEN + HEADER_LEN + first]
+            .copy_from_slice(&data[..first]);
+        self.send_chunk(HEADER_LEN + first, deadline)?;
+        for rest in data[first..].chunks(self.chunk_size) {
+            self.chunk[TYPE_LEN..TYPE_LEN + rest.len()].copy_from_slice(rest);
+            self.send_chunk(rest.len(), deadline)?;
+        }
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        let len = self.recv_chunk(deadline)?;
+        if len < HEADER_LEN {
+            return Err(IpcError::protocol(format!(
+                "Queue message of {} bytes is too short for a message header",
+                len
+            )));
+        }
+        let header = &self.chunk[TYPE_LEN..TYPE_LEN + HEADER_LEN];
+        let total = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+        if total > self.max_message_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: total,
+            });
+        }
+        let mut received = 0;
+        let mut payload = TYPE_LEN + HEADER_LEN..TYPE_LEN + len;
+        loop {
+            if received + payload.len() > total {
+                return Err(IpcError::protocol(format!(
+                    "Message is longer than its {} bytes",
+                    total
+                )));
+            }
+            self.buf[received..received + payload.len()]
+                .copy_from_slice(&self.chunk[payload.clone()]);
+            received += payload.len();
+            if received == total {
+                return Ok(&self.buf[..total]);
+            }
+            let len = self.recv_chunk(deadline).map_err(deadline::partway)?;
+            payload = TYPE_LEN..TYPE_LEN + len;
+        }
+    }
+
+    fn close(&mut self) {
+        if self.owner && !self.removed {
+            unsafe { libc::msgctl(self.id, libc::IPC_RMID, std::ptr::null_mut()) };
+            self.removed = true;
+        }
+    }
+}
+
+// The queue outlives the process, so a producer which fails before it's closed, such as while
+// starting the consumer, still removes it
+impl Drop for SysvMsgWrapper {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub type SysvMsgRunner = Runner<SysvMsgWrapper>;
@@ -0,0 +1,399 @@
+// System V shared memory guarded by System V semaphores, for comparing against software still
+// built on shmget/semop. The segment is laid out as described in the segment module, as with the
+// shmem method, but each side waits on a semaphore rather than the events in the header, which go
+// unused. The set has one semaphore for each side, raised when it has put a message in the slot.
+//
+// The segment and the semaphore set share a key which comes from a hash of the run's name, and the
+// producer removes them with IPC_RMID when it's done, or when it's dropped if it never got as far
+// as closing. The segment lasts until the consumer detaches too.
+
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::liveness::{PeerWatch, LIVENESS_INTERVAL};
+use crate::segment::{SegmentHeader, DATA_START};
+use crate::transport::{
+    wait_for_consumer, with_free_key, Endpoint, Runner, Transport, TransportConfig,
+};
+use std::io;
+use std::process::Child;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Only the owner can use the segment and the semaphores
+const IPC_MODE: libc::c_int = 0o600;
+
+// The semaphores raised by the producer and the consumer
+const PRODUCER_SEM: u16 = 0;
+const CONSUMER_SEM: u16 = 1;
+
+/// The key of the segment and the semaphores
+#[derive(Debug, Clone, clap::Args)]
+pub struct SysvShmEndpoint {
+    // Keys can be negative
+    #[arg(long, allow_negative_numbers = true)]
+    pub key: libc::key_t,
+}
+
+impl Endpoint for SysvShmEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec!["--key".to_string(), self.key.to_string()]
+    }
+}
+
+struct Segment {
+    id: libc::c_int,
+    base: *mut u8,
+    len: usize,
+}
+
+impl Segment {
+    // Create the segment, failing with EEXIST if something already holds the key
+    fn create(key: libc::key_t, len: usize) -> Result<Self> {
+        let id = unsafe { libc::shmget(key, len, libc::IPC_CREAT | libc::IPC_EXCL | IPC_MODE) };
+        Self::attach(id)
+    }
+
+    fn open(key: libc::key_t) -> Result<Self> {
+        Self::attach(unsafe { libc::shmget(key, 0, 0) })
+    }
+
+    // Map the segment, and find out how big it is
+    fn attach(id: libc::c_int) -> Result<Self> {
+        if id < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut stat: libc::shmid_ds = unsafe { std::mem::zeroed() };
+        if unsafe { libc::shmctl(id, libc::IPC_STAT, &mut stat) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let base = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+        if base as isize == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            id,
+            base: base.cast(),
+            len: stat.shm_segsz,
+        })
+    }
+
+    fn remove(&self) {
+        unsafe { libc::shmctl(self.id, libc::IPC_RMID, std::ptr::null_mut()) };
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { libc::shmdt(self.base.cast()) };
+    }
+}
+
+struct Semaphores {
+    id: libc::c_int,
+}
+
+impl Semaphores {
+    // Create the set with both semaphores lowered, failing with EEXIST if something already holds
+    // the key
+    fn create(key: libc::key_t) -> Result<Self> {
+        let id = unsafe { libc::semget(key, 2, libc::IPC_CREAT | libc::IPC_EXCL | IPC_MODE) };
+        if id < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let semaphores = Self { id };
+        for sem in [PRODUCER_SEM, CONSUMER_SEM] {
+            if unsafe { libc::semctl(id, sem as libc::c_int, libc::SETVAL, 0 as libc::c_int) } < 0 {
+                let e = io::Error::last_os_error();
+                semaphores.remove();
+                return Err(e.into());
+            }
+        }
+        Ok(semaphores)
+    }
+
+    fn open(key: libc::key_t) -> Result<Self> {
+        let id = unsafe { libc::semget(key, 2, 0) };
+        if id < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self { id })
+    }
+
+    fn raise(&self, sem: u16) -> Result<()> {
+        let mut op = libc::sembuf {
+            sem_num: sem,
+            sem_op: 1,
+            sem_flg: 0,
+        };
+        if unsafe { libc::semop(self.id, &mut op, 1) } < 0 {
+            return Err(sem_error(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    // Lower the semaphore, waiting up to `timeout` for it to be raised. Returns whether it was
+    fn lower(&self, sem: u16, timeout: Duration) -> Result<bool> {
+        let mut op = libc::sembuf {
+            sem_num: sem,
+            sem_op: -1,
+            sem_flg: 0,
+        };
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        // libc has no wrapper for semtimedop
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_semtimedop,
+                self.id,
+                &mut op as *mut libc::sembuf,
+                1,
+                &timeout as *const libc::timespec,
+            )
+        };
+        if res == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EINTR) => Ok(false),
+            _ => Err(sem_error(e)),
+        }
+    }
+
+    fn remove(&self) {
+        unsafe { libc::semctl(self.id, 0, libc::IPC_RMID) };
+    }
+}
+
+fn sem_error(e: io::Error) -> IpcError {
+    match e.raw_os_error() {
+        // The producer removed the set, so it has finished with us
+        Some(libc::EIDRM) | Some(libc::EINVAL) => IpcError::PeerGone,
+        _ => e.into(),
+    }
+}
+
+pub struct SysvShmWrapper {
+    pub key: libc::key_t,
+    pub owner: bool,
+    // Set once the producer has removed the segment and the semaphores, so dropping it afterwards
+    // can't remove objects which have since been given the same ids
+    removed: bool,
+    segment: Segment,
+    semaphores: Semaphores,
+    max_message_size: usize,
+    // Set once both sides have attached
+    peer: Option<PeerWatch>,
+    timeout: Option<Duration>,
+}
+
+impl SysvShmWrapper {
+    /// Create the segment and the semaphores under a free key for the endpoint `name`, for the
+    /// producer
+    pub fn create(name: &str, max_message_size: usize) -> Result<Self> {
+        let (key, segment, semaphores) = with_free_key(name, |key| {
+            let segment = Segment::create(key, DATA_START + max_message_size)?;
+            // A set holding the key sends us on to the next one, without the segment we just made
+            match Semaphores::create(key) {
+                Ok(semaphores) => Ok((key, segment, semaphores)),
+                Err(e) => {
+                    segment.remove();
+                    Err(e)
+                }
+            }
+        })?;
+        let header = unsafe { SegmentHeader::at(segment.base, segment.len)? };
+        header.init(max_message_size);
+        Ok(Self {
+            key,
+            owner: true,
+            removed: false,
+            segment,
+            semaphores,
+            max_message_size,
+            peer: None,
+            timeout: None,
+        })
+    }
+
+    /// Attach the producer's segment and semaphores, for the consumer
+    pub fn attach(key: libc::key_t, max_message_size: usize) -> Result<Self> {
+        let segment = Segment::open(key)?;
+        let header = unsafe { SegmentHeader::at(segment.base, segment.len)? };
+        header.validate(segment.len, max_message_size)?;
+        header
+            .pid(false)
+            .store(std::process::id(), Ordering::Release);
+        Ok(Self {
+            key,
+            owner: false,
+            removed: false,
+            segment,
+            semaphores: Semaphores::open(key)?,
+            max_message_size,
+            peer: None,
+            timeout: None,
+        })
+    }
+
+    fn header(&self) -> &SegmentHeader {
+        unsafe { &*(self.segment.base as *const SegmentHeader) }
+    }
+
+    fn our_sem(&self) -> u16 {
+        if self.owner {
+            PRODUCER_SEM
+        } else {
+            CONSUMER_SEM
+        }
+    }
+
+    fn their_sem(&self) -> u16 {
+        if self.owner {
+            CONSUMER_SEM
+        } else {
+            PRODUCER_SEM
+        }
+    }
+
+    // Start watching the other side, once it has attached and recorded its pid
+    fn watch_peer(&mut self) {
+        let peer_pid = self.header().pid(!self.owner).load(Ordering::Acquire);
+        if peer_pid != 0 {
+            self.peer = Some(PeerWatch::new(peer_pid));
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.max_message_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: data.len(),
+            });
+        }
+        let slot = unsafe {
+            std::slice::from_raw_parts_mut(self.segment.base.add(DATA_START), data.len())
+        };
+        slot.copy_from_slice(data);
+        self.header()
+            .length()
+            .store(data.len() as u32, Ordering::Release);
+        Ok(())
+    }
+
+    // Only the bytes of the last message written, not the whole slot
+    pub fn read(&self) -> Result<&[u8]> {
+        let len = self.header().length().load(Ordering::Acquire) as usize;
+        if len > self.max_message_size {
+            return Err(IpcError::SizeMismatch {
+                expected: self.max_message_size,
+                actual: len,
+            });
+        }
+        Ok(unsafe { std::slice::from_raw_parts(self.segment.base.add(DATA_START), len) })
+    }
+
+    // Wait for the other side to raise its semaphore, checking on it every LIVENESS_INTERVAL
+    fn wait(&self, deadline: Option<Instant>) -> Result<()> {
+        loop {
+            let wait = match deadline::remaining(deadline)? {
+                Some(left) => left.min(LIVENESS_INTERVAL),
+                None => LIVENESS_INTERVAL,
+            };
+            if self.semaphores.lower(self.their_sem(), wait)? {
+                return Ok(());
+            }
+            if let Some(peer) = &self.peer {
+                peer.ensure_alive()?;
+            }
+        }
+    }
+}
+
+impl Transport for SysvShmWrapper {
+    const CONSUMER: &'static str = "sysv-shm";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = SysvShmWrapper;
+
+    type Endpoint = SysvShmEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "System V shared memory with semaphores".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        SysvShmWrapper::create(&config.resolve_endpoint_name(), config.max_message_size)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        Ok(SysvShmEndpoint { key: listener.key })
+    }
+
+    fn accept(
+        mut listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer raises its semaphore once it has attached
+        let ready = wait_for_consumer(child, config.ready_timeout, || {
+            listener
+                .semaphores
+                .lower(listener.their_sem(), Duration::ZERO)
+        });
+        if let Err(e) = ready {
+            listener.close();
+            return Err(e);
+        }
+        listener.watch_peer();
+        Ok(listener)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let mut wrapper = SysvShmWrapper::attach(endpoint.key, config.max_message_size)?;
+        wrapper.watch_peer();
+        // Tell the producer we have attached
+        wrapper.semaphores.raise(wrapper.our_sem())?;
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // The other side has always read the last message before it replies, so a send never waits
+    fn send_timeout(&mut self, data: &[u8], _timeout: Option<Duration>) -> Result<()> {
+        self.write(data)?;
+        self.semaphores.raise(self.our_sem())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        self.wait(deadline::deadline(timeout))?;
+        self.read()
+    }
+
+    fn close(&mut self) {
+        if self.owner && !self.removed {
+            self.semaphores.remove();
+            self.segment.remove();
+            self.removed = true;
+        }
+    }
+}
+
+// System V objects outlive the process, so a producer which fails before it's closed, such as
+// while starting the consumer, still removes them
+impl Drop for SysvShmWrapper {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub type SysvShmRunner = Runner<SysvShmWrapper>;
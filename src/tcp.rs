@@ -1,120 +1,213 @@
-use crate::{get_payload, ExecutionResult, KB};
-use std::io::{Read, Write};
+use crate::async_transport::{wait_for_consumer_async, AsyncRunner, AsyncTransport};
+use crate::deadline::WithDeadline;
+use crate::error::Result;
+use crate::framing::{read_frame, read_frame_async, write_frame, write_frame_async, FrameProgress};
+use crate::transport::{wait_for_consumer, Endpoint, Runner, Transport, TransportConfig};
+use std::io::ErrorKind;
 use std::net::{TcpListener, TcpStream};
-use std::process::{Child, Command};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::process::Child;
+use std::time::Duration;
+
+/// The port the producer is listening on
+#[derive(Debug, Clone, clap::Args)]
+pub struct TcpEndpoint {
+    #[arg(long)]
+    pub port: u16,
+}
+
+impl Endpoint for TcpEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec!["--port".to_string(), self.port.to_string()]
+    }
+}
 
 pub struct TcpStreamWrapper {
     pub port: u16,
     pub server: bool,
     pub stream: TcpStream,
+    buf: Vec<u8>,
+    timeout: Option<Duration>,
 }
 
 impl TcpStreamWrapper {
-    pub fn from_port(port: u16, tcp_nodelay: bool) -> Self {
-        let stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
-        stream.set_nodelay(tcp_nodelay).unwrap();
+    pub fn from_port(port: u16, tcp_nodelay: bool, max_message_size: usize) -> Result<Self> {
+        let stream = TcpStream::connect(format!("127.0.0.1:{}", port))?;
+        stream.set_nodelay(tcp_nodelay)?;
 
-        Self {
+        Ok(Self {
             port,
             server: false,
             stream,
-        }
+            buf: vec![0; max_message_size],
+            timeout: None,
+        })
     }
 
-    pub fn from_listener(tcp_listener: TcpListener, tcp_nodelay: bool) -> TcpStreamWrapper {
-        let addr = tcp_listener.local_addr().unwrap();
-        let (stream, _socket) = tcp_listener.accept().unwrap();
-        stream.set_nodelay(tcp_nodelay).unwrap();
+    pub fn from_listener(
+        tcp_listener: TcpListener,
+        tcp_nodelay: bool,
+        max_message_size: usize,
+    ) -> Result<TcpStreamWrapper> {
+        let (stream, _socket) = tcp_listener.accept()?;
+        Self::from_stream(&tcp_listener, stream, tcp_nodelay, max_message_size)
+    }
 
-        Self {
+    fn from_stream(
+        tcp_listener: &TcpListener,
+        stream: TcpStream,
+        tcp_nodelay: bool,
+        max_message_size: usize,
+    ) -> Result<TcpStreamWrapper> {
+        let addr = tcp_listener.local_addr()?;
+        stream.set_nodelay(tcp_nodelay)?;
+
+        Ok(Self {
             port: addr.port(),
             server: true,
             stream,
-        }
+            buf: vec![0; max_message_size],
+            timeout: None,
+        })
     }
 }
 
-pub struct TcpRunner {
-    child_proc: Option<Child>,
-    wrapper: TcpStreamWrapper,
-    tcp_nodelay: bool,
-    data_size: usize,
-    request_data: Vec<u8>,
-    response_data: Vec<u8>,
-}
+impl Transport for TcpStreamWrapper {
+    const CONSUMER: &'static str = "tcp";
+
+    const VARIABLE_LENGTH: bool = true;
 
-impl TcpRunner {
-    pub fn new(start_child: bool, tcp_nodelay: bool, data_size: usize) -> TcpRunner {
-        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-        let port = listener.local_addr().unwrap().port();
-        let exe = crate::executable_path("tcp_consumer");
-        let child_proc = if start_child {
-            let res = Some(
-                Command::new(exe)
-                    .args(&[
-                        port.to_string(),
-                        tcp_nodelay.to_string(),
-                        data_size.to_string(),
-                    ])
-                    .spawn()
-                    .unwrap(),
-            );
-            // Awkward sleep to wait for consumer to be ready
-            sleep(Duration::from_secs(2));
-            res
-        } else {
-            None
-        };
-
-        let stream = TcpStreamWrapper::from_listener(listener, tcp_nodelay);
-
-        let (request_data, response_data) = get_payload(data_size);
-
-        Self {
-            child_proc,
-            wrapper: stream,
-            tcp_nodelay,
-            data_size,
-            request_data,
-            response_data,
-        }
-    }
-
-    pub fn run(&mut self, n: usize, print: bool) {
-        let start = Instant::now();
-        let mut buf = vec![0; self.data_size];
-        for _ in 0..n {
-            self.wrapper.stream.write(&self.request_data).unwrap();
-            self.wrapper.stream.read_exact(&mut buf).unwrap();
-
-            #[cfg(debug_assertions)]
-            if buf.ne(&self.response_data) {
-                panic!("Sent request didn't get response")
+    type Listener = TcpListener;
+
+    type Endpoint = TcpEndpoint;
+
+    fn name(config: &TransportConfig) -> String {
+        format!("TCP - nodelay={}", config.tcp_nodelay)
+    }
+
+    fn listen(_config: &TransportConfig) -> Result<Self::Listener> {
+        Ok(TcpListener::bind("127.0.0.1:0")?)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<TcpEndpoint> {
+        Ok(TcpEndpoint {
+            port: listener.local_addr()?.port(),
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer connecting is its ready signal
+        listener.set_nonblocking(true)?;
+        let mut stream = None;
+        wait_for_consumer(child, config.ready_timeout, || match listener.accept() {
+            Ok((accepted, _socket)) => {
+                stream = Some(accepted);
+                Ok(true)
             }
-        }
-        if print {
-            let elapsed = start.elapsed();
-            let res = ExecutionResult::new(
-                format!(
-                    "TCP - nodelay={} - {}KB",
-                    self.tcp_nodelay,
-                    self.data_size / KB
-                )
-                .to_string(),
-                elapsed,
-                n,
-            );
-            res.print_info();
-        }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        })?;
+        let stream = stream.unwrap();
+        stream.set_nonblocking(false)?;
+        TcpStreamWrapper::from_stream(
+            &listener,
+            stream,
+            config.tcp_nodelay,
+            config.max_message_size,
+        )
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        TcpStreamWrapper::from_port(endpoint.port, config.tcp_nodelay, config.max_message_size)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let mut stream = WithDeadline::new(&mut self.stream, timeout);
+        write_frame(&mut stream, data, self.buf.len())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let mut stream = WithDeadline::new(&mut self.stream, timeout);
+        let len = read_frame(&mut stream, &mut self.buf)?;
+        Ok(&self.buf[..len])
+    }
+}
+
+pub type TcpRunner = Runner<TcpStreamWrapper>;
+
+pub struct AsyncTcpStreamWrapper {
+    pub stream: tokio::net::TcpStream,
+    buf: Vec<u8>,
+    progress: FrameProgress,
+}
+
+impl AsyncTcpStreamWrapper {
+    fn from_stream(
+        stream: tokio::net::TcpStream,
+        tcp_nodelay: bool,
+        max_message_size: usize,
+    ) -> Result<Self> {
+        stream.set_nodelay(tcp_nodelay)?;
+        Ok(Self {
+            stream,
+            buf: vec![0; max_message_size],
+            progress: FrameProgress::default(),
+        })
     }
 }
 
-impl Drop for TcpRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut c) = self.child_proc {
-            c.kill().unwrap();
-        }
+impl AsyncTransport for AsyncTcpStreamWrapper {
+    type Blocking = TcpStreamWrapper;
+
+    type Listener = tokio::net::TcpListener;
+
+    async fn listen(_config: &TransportConfig) -> Result<Self::Listener> {
+        Ok(tokio::net::TcpListener::bind("127.0.0.1:0").await?)
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<TcpEndpoint> {
+        Ok(TcpEndpoint {
+            port: listener.local_addr()?.port(),
+        })
+    }
+
+    async fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        // The consumer connecting is its ready signal
+        let (stream, _socket) = wait_for_consumer_async(child, config.ready_timeout, async {
+            Ok(listener.accept().await?)
+        })
+        .await?;
+        AsyncTcpStreamWrapper::from_stream(stream, config.tcp_nodelay, config.max_message_size)
+    }
+
+    async fn connect(config: &TransportConfig, endpoint: &TcpEndpoint) -> Result<Self> {
+        let stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", endpoint.port)).await?;
+        AsyncTcpStreamWrapper::from_stream(stream, config.tcp_nodelay, config.max_message_size)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        write_frame_async(&mut self.stream, data, self.buf.len()).await
+    }
+
+    async fn recv(&mut self) -> Result<&[u8]> {
+        let len = read_frame_async(&mut self.stream, &mut self.buf, &mut self.progress).await?;
+        Ok(&self.buf[..len])
     }
 }
+
+pub type AsyncTcpRunner = AsyncRunner<AsyncTcpStreamWrapper>;
@@ -0,0 +1,559 @@
+use crate::error::{IpcError, Result};
+use crate::futex::WaitStrategy;
+use crate::peer_cred::{PeerPolicy, DEFAULT_SOCKET_MODE};
+use crate::serve::{serve, Shutdown};
+use crate::{core_usage, cpu_time, cpu_warmup, get_mixed_sizes, get_payload, ExecutionResult, KB};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long to back off between checks for the consumer's ready signal
+pub(crate) const READY_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Slots in each direction of a shared memory ring, unless configured otherwise
+pub const DEFAULT_RING_SLOTS: usize = 8;
+
+// Number of distinct message sizes cycled through in mixed size mode
+const MIXED_SIZES: usize = 64;
+
+// Runners created by this process so far, so each gets its own endpoint name
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Settings shared by the producer and the consumer of a transport. The consumer gets the ones
+/// it needs as `serve` arguments, the rest are skipped.
+#[derive(Debug, Clone, clap::Args)]
+pub struct TransportConfig {
+    #[arg(long)]
+    pub data_size: usize,
+    /// Largest message the transport will carry, defaults to `data_size`
+    #[arg(long)]
+    pub max_message_size: usize,
+    /// Send requests of random sizes up to `data_size` instead of always `data_size`. Only the
+    /// producer uses this
+    #[arg(skip)]
+    pub mixed_sizes: bool,
+    /// Keep as many requests in flight as the transport allows, rather than waiting for each
+    /// response before the next request. Only the producer uses this
+    #[arg(skip)]
+    pub streaming: bool,
+    /// Slots in each ring of the shared memory ring transport. The consumer reads it from the
+    /// segment, so only the producer uses this
+    #[arg(skip = DEFAULT_RING_SLOTS)]
+    pub ring_slots: usize,
+    #[arg(long, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+    /// How the shmem and mmap transports wait for the other side: spin, block, or adaptive:N to
+    /// spin N times and then block
+    #[arg(long, default_value_t)]
+    pub wait_strategy: WaitStrategy,
+    /// Longest each send or recv may block, on both sides. Unbounded if not set
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub io_timeout: Option<Duration>,
+    /// How long the producer waits for the consumer to signal it is ready
+    #[arg(skip = DEFAULT_READY_TIMEOUT)]
+    pub ready_timeout: Duration,
+    /// How long the producer waits for the consumer to exit after asking it to, before killing it
+    #[arg(skip = DEFAULT_SHUTDOWN_TIMEOUT)]
+    pub shutdown_timeout: Duration,
+    /// Name the socket files, mapped file or services of a transport are created under. Each
+    /// runner makes up a unique one if this isn't set. The consumer is handed the name through
+    /// its endpoint, so only the producer uses this
+    #[arg(skip)]
+    pub endpoint_name: Option<String>,
+    /// Which peers the unix socket transports accept, checked by both sides
+    #[arg(skip = PeerPolicy::same_user())]
+    pub peer_policy: PeerPolicy,
+    /// Permissions for the unix socket files a transport binds
+    #[arg(skip = DEFAULT_SOCKET_MODE)]
+    pub socket_mode: u32,
+}
+
+impl TransportConfig {
+    pub fn new(data_size: usize) -> Self {
+        Self {
+            data_size,
+            max_message_size: data_size,
+            mixed_sizes: false,
+            streaming: false,
+            ring_slots: DEFAULT_RING_SLOTS,
+            tcp_nodelay: true,
+            wait_strategy: WaitStrategy::Spin,
+            io_timeout: None,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            endpoint_name: None,
+            peer_policy: PeerPolicy::same_user(),
+            socket_mode: DEFAULT_SOCKET_MODE,
+        }
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn mixed_sizes(mut self, mixed_sizes: bool) -> Self {
+        self.mixed_sizes = mixed_sizes;
+        self
+    }
+
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    pub fn ring_slots(mut self, ring_slots: usize) -> Self {
+        self.ring_slots = ring_slots;
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    pub fn wait_strategy(mut self, wait_strategy: WaitStrategy) -> Self {
+        self.wait_strategy = wait_strategy;
+        self
+    }
+
+    pub fn io_timeout(mut self, io_timeout: Option<Duration>) -> Self {
+        self.io_timeout = io_timeout;
+        self
+    }
+
+    pub fn ready_timeout(mut self, ready_timeout: Duration) -> Self {
+        self.ready_timeout = ready_timeout;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    pub fn endpoint_name(mut self, endpoint_name: impl Into<String>) -> Self {
+        self.endpoint_name = Some(endpoint_name.into());
+        self
+    }
+
+    pub fn peer_policy(mut self, peer_policy: PeerPolicy) -> Self {
+        self.peer_policy = peer_policy;
+        self
+    }
+
+    pub fn socket_mode(mut self, socket_mode: u32) -> Self {
+        self.socket_mode = socket_mode;
+        self
+    }
+
+    // The configured endpoint name, or one no other run on this host will be using
+    pub(crate) fn resolve_endpoint_name(&self) -> String {
+        match &self.endpoint_name {
+            Some(name) => name.clone(),
+            None => format!(
+                "ipc-{}-{}",
+                std::process::id(),
+                RUNS.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    // Arguments handed to the consumer, ahead of the transport's endpoint
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--data-size".to_string(),
+            self.data_size.to_string(),
+            "--max-message-size".to_string(),
+            self.max_message_size.to_string(),
+            "--tcp-nodelay".to_string(),
+            self.tcp_nodelay.to_string(),
+            "--wait-strategy".to_string(),
+            self.wait_strategy.to_string(),
+        ];
+        if let Some(io_timeout) = self.io_timeout {
+            args.push("--io-timeout".to_string());
+            args.push(humantime::format_duration(io_timeout).to_string());
+        }
+        args
+    }
+}
+
+/// Whatever the consumer needs to reach the producer's listener, such as a port or a shared
+/// memory id. It's passed as arguments of the consumer's `serve` subcommand.
+pub trait Endpoint: clap::Args + clap::FromArgMatches {
+    fn to_args(&self) -> Vec<String>;
+}
+
+/// For transports where the consumer finds the producer at a fixed place
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct NoEndpoint {}
+
+impl Endpoint for NoEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// For transports which find each other by name, such as a socket file or a service
+#[derive(Debug, Clone, clap::Args)]
+pub struct NamedEndpoint {
+    #[arg(long)]
+    pub name: String,
+}
+
+impl Endpoint for NamedEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec!["--name".to_string(), self.name.clone()]
+    }
+}
+
+// Where a transport keeps a file belonging to the endpoint `name`
+pub(crate) fn endpoint_path(name: &str, suffix: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}{}", name, suffix))
+}
+
+// Keys tried for an endpoint before giving up
+const KEY_ATTEMPTS: u32 = 64;
+
+// Create System V objects for the endpoint `name` with `create`, which must use IPC_EXCL. Keys are
+// plain numbers, so the first one tried is a hash of the name, and if something already holds a
+// key the next one along is tried. Objects other software created are never touched
+pub(crate) fn with_free_key<T>(
+    name: &str,
+    mut create: impl FnMut(libc::key_t) -> Result<T>,
+) -> Result<T> {
+    // FNV-1a
+    let hash = name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    for attempt in 0..KEY_ATTEMPTS {
+        let key = hash.wrapping_add(attempt) as libc::key_t;
+        // Which would ask for a new private object rather than a shared one
+        if key == libc::IPC_PRIVATE {
+            continue;
+        }
+        match create(key) {
+            Err(IpcError::Os(e)) if e.raw_os_error() == Some(libc::EEXIST) => continue,
+            created => return created,
+        }
+    }
+    Err(IpcError::protocol(format!(
+        "No free System V key among the {} tried for {:?}",
+        KEY_ATTEMPTS, name
+    )))
+}
+
+/// A bidirectional channel between the producer and a consumer process.
+///
+/// The producer side is set up in two steps: [`Transport::listen`] creates whatever the consumer
+/// needs to find us, then [`Transport::accept`] completes the connection once the consumer has
+/// been spawned. The consumer side is created in one go by [`Transport::connect`].
+pub trait Transport: Sized {
+    /// Name of the `serve` subcommand which runs the consumer for this transport
+    const CONSUMER: &'static str;
+
+    /// Whether responses can be checked against the expected payload
+    const RELIABLE: bool = true;
+
+    /// Whether `recv` returns messages exactly as they were sent, whatever their size
+    const VARIABLE_LENGTH: bool = false;
+
+    /// Producer-side state which exists before the consumer is running
+    type Listener;
+
+    type Endpoint: Endpoint;
+
+    /// Label used when printing results
+    fn name(config: &TransportConfig) -> String;
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener>;
+
+    /// What the consumer needs to reach the listener
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint>;
+
+    /// Any other setup the consumer's process needs, such as piped stdio
+    fn prepare_consumer(_listener: &Self::Listener, _command: &mut Command) -> Result<()> {
+        Ok(())
+    }
+
+    /// Wait up to `config.ready_timeout` for the consumer's ready signal, then finish connecting
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self>;
+
+    /// Consumer side, `endpoint` is what [`Transport::endpoint`] gave the producer. Once
+    /// connected the consumer signals the producer that it is ready
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self>;
+
+    /// The timeout [`Transport::send`] and [`Transport::recv`] use, `None` if they can block
+    /// for as long as it takes
+    fn timeout(&self) -> Option<Duration>;
+
+    /// Set the timeout for every send and recv on this channel which doesn't give its own
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+
+    /// Send with the channel's timeout
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.send_timeout(data, self.timeout())
+    }
+
+    /// Block until a whole message has arrived, or the channel's timeout runs out
+    fn recv(&mut self) -> Result<&[u8]> {
+        self.recv_timeout(self.timeout())
+    }
+
+    /// Send, failing with [`IpcError::Timeout`] if the message can't be handed over in time.
+    /// Stream transports wait for room before each write rather than for the whole message, and a
+    /// message cut short leaves the stream out of step, so they should be dropped after a timeout
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()>;
+
+    /// Wait up to `timeout` for a whole message, failing with [`IpcError::Timeout`] if none
+    /// arrives, in which case it can be called again. A stream which times out partway through a
+    /// message is out of step, so it fails with a protocol error instead and should be dropped
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]>;
+
+    /// Anything the transport counted which is worth printing after a run, such as how much it
+    /// had to retransmit
+    fn report(&self) -> Option<String> {
+        None
+    }
+
+    /// How many requests may be sent before a response has to be read, when streaming. The
+    /// consumer must be able to reply to all of them without the producer reading anything
+    fn max_in_flight(&self) -> usize {
+        1
+    }
+
+    /// Ask the consumer to stop. An empty message is never a request, so it's used as the signal
+    fn shutdown(&mut self) -> Result<()> {
+        self.send(&[])
+    }
+
+    /// Release anything the transport left behind, such as socket files
+    fn close(&mut self) {}
+}
+
+pub struct Runner<T: Transport> {
+    child_proc: Option<Child>,
+    transport: T,
+    shutdown_timeout: Duration,
+    name: String,
+    data_size: usize,
+    // Request sizes to cycle through, just `data_size` unless in mixed size mode
+    sizes: Vec<usize>,
+    // Requests sent ahead of their responses, 1 unless streaming
+    window: usize,
+    request_data: Vec<u8>,
+    response_data: Vec<u8>,
+}
+
+impl<T: Transport> Runner<T> {
+    pub fn new(start_child: bool, config: TransportConfig) -> Result<Self> {
+        check_config::<T>(&config)?;
+
+        let listener = T::listen(&config)?;
+
+        let mut child_proc = if start_child {
+            let mut command = crate::consumer_command(T::CONSUMER);
+            command.args(config.to_args());
+            command.args(T::endpoint(&listener)?.to_args());
+            T::prepare_consumer(&listener, &mut command)?;
+            Some(command.spawn()?)
+        } else {
+            None
+        };
+
+        let mut transport = match T::accept(listener, &config, child_proc.as_mut()) {
+            Ok(transport) => transport,
+            Err(e) => {
+                if let Some(ref mut child) = child_proc {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(e);
+            }
+        };
+
+        transport.set_timeout(config.io_timeout);
+
+        let (request_data, response_data) = get_payload(config.data_size);
+        let mut name = T::name(&config);
+        let sizes = request_sizes(&config, &mut name);
+        let window = if config.streaming {
+            name.push_str(" - streaming");
+            transport.max_in_flight()
+        } else {
+            1
+        };
+
+        Ok(Self {
+            child_proc,
+            transport,
+            shutdown_timeout: config.shutdown_timeout,
+            name,
+            data_size: config.data_size,
+            sizes,
+            window,
+            request_data,
+            response_data,
+        })
+    }
+
+    pub fn run(&mut self, n: usize, print: bool) -> Result<()> {
+        let start = Instant::now();
+        let cpu_start = cpu_time();
+        let mut sent = 0;
+        for received in 0..n {
+            while sent < n && sent - received < self.window {
+                let size = self.sizes[sent % self.sizes.len()];
+                self.transport.send(&self.request_data[..size])?;
+                sent += 1;
+            }
+            // Responses come back in the order the requests went out
+            let size = self.sizes[received % self.sizes.len()];
+            let response = self.transport.recv()?;
+
+            debug_assert!(
+                !T::RELIABLE || response == &self.response_data[..size],
+                "Sent request didn't get response"
+            );
+        }
+        if print {
+            let elapsed = start.elapsed();
+            let res = ExecutionResult::new(
+                format!("{} - {}KB", self.name, self.data_size / KB),
+                elapsed,
+                n,
+            )
+            .cpu(cpu_time() - cpu_start);
+            res.print_info();
+            if let Some(report) = self.transport.report() {
+                println!("\t{}", report);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Drop for Runner<T> {
+    fn drop(&mut self) {
+        // A consumer we didn't start is still told to stop, we just can't wait for it
+        let _ = self.transport.shutdown();
+        if let Some(ref mut child) = self.child_proc {
+            stop_consumer(child, self.shutdown_timeout);
+        }
+        self.transport.close();
+    }
+}
+
+pub(crate) fn check_config<T: Transport>(config: &TransportConfig) -> Result<()> {
+    if config.mixed_sizes && !T::VARIABLE_LENGTH {
+        return Err(IpcError::protocol(format!(
+            "{} can't carry mixed size messages",
+            T::name(config)
+        )));
+    }
+    if config.data_size > config.max_message_size {
+        return Err(IpcError::SizeMismatch {
+            expected: config.max_message_size,
+            actual: config.data_size,
+        });
+    }
+    Ok(())
+}
+
+// Request sizes a runner cycles through, noting mixed size mode in its name
+pub(crate) fn request_sizes(config: &TransportConfig, name: &mut String) -> Vec<usize> {
+    if config.mixed_sizes {
+        name.push_str(" - mixed sizes");
+        get_mixed_sizes(config.data_size, MIXED_SIZES)
+    } else {
+        vec![config.data_size]
+    }
+}
+
+/// Poll `ready` until the consumer has signalled that it is up. Fails with
+/// [`IpcError::PeerGone`] if the consumer exits first, or [`IpcError::Timeout`] after `timeout`
+pub(crate) fn wait_for_consumer(
+    mut child: Option<&mut Child>,
+    timeout: Duration,
+    mut ready: impl FnMut() -> Result<bool>,
+) -> Result<()> {
+    let start = Instant::now();
+    while !ready()? {
+        if let Some(child) = child.as_deref_mut() {
+            if child.try_wait()?.is_some() {
+                return Err(IpcError::PeerGone);
+            }
+        }
+        if start.elapsed() > timeout {
+            return Err(IpcError::Timeout);
+        }
+        sleep(READY_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Give the consumer `timeout` to exit once it has been asked to, then kill it
+pub(crate) fn stop_consumer(child: &mut Child, timeout: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        match child.try_wait() {
+            Ok(Some(_)) | Err(_) => return,
+            Ok(None) => sleep(READY_POLL_INTERVAL),
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Echo loop run by `ipc serve`, with the config and endpoint [`Runner::new`] passed it.
+/// Returns once the producer asks it to stop or goes away, reporting what it served on stderr.
+/// Each response is cut to the length of its request
+pub fn run_consumer<T: Transport>(config: TransportConfig, endpoint: T::Endpoint) -> Result<()> {
+    core_affinity::set_for_current(core_affinity::CoreId { id: 0 });
+
+    let mut transport = T::connect(&config, &endpoint)?;
+    transport.set_timeout(config.io_timeout);
+    let (request_data, response_data) = get_payload(config.data_size);
+
+    cpu_warmup();
+
+    let echo = |request: &[u8]| {
+        let size = request.len().min(response_data.len());
+
+        debug_assert!(
+            !T::RELIABLE || request == &request_data[..size],
+            "Didn't receive valid request"
+        );
+
+        &response_data[..size]
+    };
+    let start = Instant::now();
+    let cpu_start = cpu_time();
+    let stats = serve(&mut transport, echo, &Shutdown::new())?;
+    let cpu = cpu_time() - cpu_start;
+    transport.close();
+    eprintln!(
+        "\tConsumer served {} requests, {} bytes received, {} bytes sent",
+        stats.requests, stats.bytes_received, stats.bytes_sent
+    );
+    eprintln!(
+        "\tConsumer used {} of CPU, {:.0}% of a core",
+        humantime::Duration::from(cpu),
+        core_usage(cpu, start.elapsed())
+    );
+    if let Some(report) = transport.report() {
+        eprintln!("\tConsumer {}", report);
+    }
+    Ok(())
+}
@@ -1,151 +1,336 @@
-use crate::{get_payload, ExecutionResult, KB};
+use crate::async_transport::{wait_for_consumer_async, AsyncRunner, AsyncTransport};
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::reliable::{LinkStats, ReliableLink, TICK};
+use crate::transport::{wait_for_consumer, Endpoint, Runner, Transport, TransportConfig};
+use crate::KB;
 
+use std::io::ErrorKind;
 use std::net::UdpSocket;
-use std::process::{Child, Command};
-use std::thread::sleep;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::process::Child;
 use std::time::{Duration, Instant};
 
-const MAX_CHUNK_SIZE: usize = 8 * KB;
+// Most we put in one datagram, header included
+const DATAGRAM_SIZE: usize = 8 * KB;
+// Sent bare, before the reliability protocol starts
+const READY: u8 = 1;
 
+/// The ports of both sockets, the producer's already bound and the consumer's to bind
+#[derive(Debug, Clone, clap::Args)]
+pub struct UdpEndpoint {
+    #[arg(long)]
+    pub producer_port: u16,
+    #[arg(long)]
+    pub consumer_port: u16,
+}
+
+impl Endpoint for UdpEndpoint {
+    fn to_args(&self) -> Vec<String> {
+        vec![
+            "--producer-port".to_string(),
+            self.producer_port.to_string(),
+            "--consumer-port".to_string(),
+            self.consumer_port.to_string(),
+        ]
+    }
+}
+
+// Make room for a whole message in the socket's receive buffer, so its chunks aren't dropped when
+// they arrive faster than the receiver is reading. The kernel caps this at net.core.rmem_max, past
+// which NACKs recover what was dropped
+fn reserve_recv_buffer(socket: BorrowedFd<'_>, max_message_size: usize) -> Result<()> {
+    let size = max_message_size.saturating_mul(2).min(i32::MAX as usize) as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// UDP with the [`crate::reliable`] protocol on top, so messages arrive whole, once and in order
 pub struct UdpStreamWrapper {
     pub our_port: u16,
     pub server: bool,
     pub socket: UdpSocket,
-    pub data_size: usize,
+    link: ReliableLink,
+    datagram: Vec<u8>,
+    timeout: Option<Duration>,
 }
 
 impl UdpStreamWrapper {
-    pub fn from_port(port: u16, data_size: usize) -> Self {
-        let socket = UdpSocket::bind(format!("127.0.0.1:{}", port)).unwrap();
-        let our_port = socket.local_addr().unwrap().port();
-        socket
-            .set_read_timeout(Some(Duration::from_millis(100)))
-            .unwrap();
-
-        Self {
+    pub fn from_port(port: u16, max_message_size: usize) -> Result<Self> {
+        let socket = UdpSocket::bind(format!("127.0.0.1:{}", port))?;
+        let our_port = socket.local_addr()?.port();
+        socket.set_read_timeout(Some(TICK))?;
+        reserve_recv_buffer(socket.as_fd(), max_message_size)?;
+
+        Ok(Self {
             our_port,
             socket,
             server: false,
-            data_size,
-        }
+            link: ReliableLink::new(DATAGRAM_SIZE, max_message_size),
+            datagram: vec![0; DATAGRAM_SIZE],
+            timeout: None,
+        })
     }
 
-    pub fn new(data_size: usize) -> UdpStreamWrapper {
-        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
-        let our_port = socket.local_addr().unwrap().port();
-        socket
-            .set_read_timeout(Some(Duration::from_millis(100)))
-            .unwrap();
+    pub fn new(max_message_size: usize) -> Result<UdpStreamWrapper> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let our_port = socket.local_addr()?.port();
+        socket.set_read_timeout(Some(TICK))?;
+        reserve_recv_buffer(socket.as_fd(), max_message_size)?;
 
-        Self {
+        Ok(Self {
             our_port,
             server: true,
             socket,
-            data_size,
+            link: ReliableLink::new(DATAGRAM_SIZE, max_message_size),
+            datagram: vec![0; DATAGRAM_SIZE],
+            timeout: None,
+        })
+    }
+
+    /// Losses and retransmissions so far
+    pub fn stats(&self) -> LinkStats {
+        self.link.stats()
+    }
+
+    fn flush(&mut self, deadline: Option<Instant>) -> Result<()> {
+        while let Some(datagram) = self.link.next_datagram(&mut self.datagram) {
+            deadline::wait_writable(self.socket.as_fd(), deadline)?;
+            self.socket.send(datagram)?;
         }
+        Ok(())
     }
 
-    pub fn send(&self, data: &Vec<u8>) {
-        for chunk in data.chunks(MAX_CHUNK_SIZE) {
-            loop {
-                match self.socket.send(chunk) {
-                    Ok(_) => break,
-                    Err(_) => continue,
-                }
+    // Send whatever the link has queued, then wait up to a tick for a datagram to take in, so the
+    // link's timers run even when nothing arrives
+    fn pump(&mut self, deadline: Option<Instant>) -> Result<()> {
+        self.flush(deadline)?;
+        let tick = Instant::now() + TICK;
+        let wake = deadline.map_or(tick, |deadline| deadline.min(tick));
+        match deadline::wait_readable(self.socket.as_fd(), Some(wake)) {
+            Ok(()) => {
+                let size = self.socket.recv(&mut self.datagram)?;
+                self.link.on_datagram(&self.datagram[..size])?;
             }
+            Err(IpcError::Timeout) => deadline::check(deadline)?,
+            Err(e) => return Err(e),
         }
+        self.link.poll_timers();
+        self.flush(deadline)
     }
+}
+
+impl Transport for UdpStreamWrapper {
+    const CONSUMER: &'static str = "udp";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    // Our socket and the port the consumer will bind
+    type Listener = (UdpStreamWrapper, u16);
 
-    pub fn recv(&self) -> Vec<u8> {
-        let mut received_data = Vec::new();
-        loop {
-            let mut buf = vec![0; MAX_CHUNK_SIZE];
-            match self.socket.recv(&mut buf) {
-                Ok(size) => {
-                    received_data.extend_from_slice(&buf[..size]);
-                    if received_data.len() >= self.data_size {
-                        break;
-                    }
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    if !received_data.is_empty() {
-                        break;
-                    }
-                }
-                Err(e) => panic!("Error receiving data {e}"),
+    type Endpoint = UdpEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "UDP".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        let wrapper = UdpStreamWrapper::new(config.max_message_size)?;
+        let their_port = portpicker::pick_unused_port()
+            .ok_or_else(|| IpcError::backend("No free UDP port for the consumer"))?;
+        Ok((wrapper, their_port))
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<Self::Endpoint> {
+        let (wrapper, their_port) = listener;
+        Ok(UdpEndpoint {
+            producer_port: wrapper.our_port,
+            consumer_port: *their_port,
+        })
+    }
+
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let (wrapper, their_port) = listener;
+        // The consumer sends a ready datagram once its socket is bound. Reads time out, so this
+        // doesn't block for long
+        wait_for_consumer(child, config.ready_timeout, || {
+            match wrapper.socket.recv(&mut [0; 1]) {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+                Err(e) => Err(e.into()),
             }
+        })?;
+        wrapper
+            .socket
+            .connect(format!("127.0.0.1:{}", their_port))?;
+        Ok(wrapper)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &Self::Endpoint) -> Result<Self> {
+        let wrapper = UdpStreamWrapper::from_port(endpoint.consumer_port, config.max_message_size)?;
+        wrapper
+            .socket
+            .connect(format!("127.0.0.1:{}", endpoint.producer_port))?;
+        wrapper.socket.send(&[READY])?;
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let deadline = deadline::deadline(timeout);
+        while self.link.awaiting_ack() {
+            self.pump(deadline)?;
+        }
+        self.link.start_send(data)?;
+        self.flush(deadline)
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        while !self.link.has_message() {
+            self.pump(deadline)?;
         }
-        received_data
+        Ok(self.link.take_message())
+    }
+
+    fn report(&self) -> Option<String> {
+        Some(self.stats().to_string())
     }
 }
 
-pub struct UdpRunner {
-    child_proc: Option<Child>,
-    wrapper: UdpStreamWrapper,
-    data_size: usize,
-    request_data: Vec<u8>,
-    #[allow(unused)]
-    response_data: Vec<u8>,
+pub type UdpRunner = Runner<UdpStreamWrapper>;
+
+pub struct AsyncUdpStreamWrapper {
+    pub socket: tokio::net::UdpSocket,
+    link: ReliableLink,
+    datagram: Vec<u8>,
+}
+
+impl AsyncUdpStreamWrapper {
+    pub async fn from_port(port: u16, max_message_size: usize) -> Result<Self> {
+        let socket = tokio::net::UdpSocket::bind(format!("127.0.0.1:{}", port)).await?;
+        reserve_recv_buffer(socket.as_fd(), max_message_size)?;
+        Ok(Self {
+            socket,
+            link: ReliableLink::new(DATAGRAM_SIZE, max_message_size),
+            datagram: vec![0; DATAGRAM_SIZE],
+        })
+    }
+
+    /// Losses and retransmissions so far
+    pub fn stats(&self) -> LinkStats {
+        self.link.stats()
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        while let Some(datagram) = self.link.next_datagram(&mut self.datagram) {
+            self.socket.send(datagram).await?;
+        }
+        Ok(())
+    }
+
+    // Same as the blocking version's, see [`UdpStreamWrapper::pump`]
+    async fn pump(&mut self) -> Result<()> {
+        self.flush().await?;
+        if let Ok(size) = tokio::time::timeout(TICK, self.socket.recv(&mut self.datagram)).await {
+            self.link.on_datagram(&self.datagram[..size?])?;
+        }
+        self.link.poll_timers();
+        self.flush().await
+    }
 }
 
-impl UdpRunner {
-    pub fn new(start_child: bool, data_size: usize) -> UdpRunner {
-        let wrapper = UdpStreamWrapper::new(data_size);
-        let their_port = portpicker::pick_unused_port().unwrap();
-        let exe = crate::executable_path("udp_consumer");
-        let child_proc = if start_child {
-            Some(
-                Command::new(exe)
-                    .args(&[
-                        wrapper.our_port.to_string(),
-                        their_port.to_string(),
-                        data_size.to_string(),
-                    ])
-                    .spawn()
-                    .unwrap(),
-            )
-        } else {
-            None
-        };
-        // Awkward sleep to make sure the child proc is ready
-        sleep(Duration::from_secs(2));
+impl AsyncTransport for AsyncUdpStreamWrapper {
+    type Blocking = UdpStreamWrapper;
+
+    // Our socket and the port the consumer will bind
+    type Listener = (AsyncUdpStreamWrapper, u16);
+
+    async fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        let wrapper = AsyncUdpStreamWrapper::from_port(0, config.max_message_size).await?;
+        let their_port = portpicker::pick_unused_port()
+            .ok_or_else(|| IpcError::backend("No free UDP port for the consumer"))?;
+        Ok((wrapper, their_port))
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<UdpEndpoint> {
+        let (wrapper, their_port) = listener;
+        Ok(UdpEndpoint {
+            producer_port: wrapper.socket.local_addr()?.port(),
+            consumer_port: *their_port,
+        })
+    }
+
+    async fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let (wrapper, their_port) = listener;
+        // The consumer sends a ready datagram once its socket is bound
+        wait_for_consumer_async(child, config.ready_timeout, async {
+            Ok(wrapper.socket.recv(&mut [0; 1]).await?)
+        })
+        .await?;
         wrapper
             .socket
             .connect(format!("127.0.0.1:{}", their_port))
-            .expect("Child process can't connect");
+            .await?;
+        Ok(wrapper)
+    }
 
-        let (request_data, response_data) = get_payload(data_size);
+    async fn connect(config: &TransportConfig, endpoint: &UdpEndpoint) -> Result<Self> {
+        let wrapper =
+            AsyncUdpStreamWrapper::from_port(endpoint.consumer_port, config.max_message_size)
+                .await?;
+        wrapper
+            .socket
+            .connect(format!("127.0.0.1:{}", endpoint.producer_port))
+            .await?;
+        wrapper.socket.send(&[READY]).await?;
+        Ok(wrapper)
+    }
 
-        Self {
-            child_proc,
-            wrapper,
-            data_size,
-            request_data,
-            response_data,
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        while self.link.awaiting_ack() {
+            self.pump().await?;
         }
+        self.link.start_send(data)?;
+        self.flush().await
     }
 
-    pub fn run(&mut self, n: usize, print: bool) {
-        let start = Instant::now();
-        for _ in 0..n {
-            self.wrapper.send(&self.request_data);
-            let _response = self.wrapper.recv();
-            // if !response.eq(&self.response_data) {
-            //     panic!("Sent request didn't get expected response")
-            // }
-        }
-        if print {
-            let elapsed = start.elapsed();
-            let res = ExecutionResult::new(format!("UDP - {}KB", self.data_size / KB), elapsed, n);
-            res.print_info();
+    async fn recv(&mut self) -> Result<&[u8]> {
+        while !self.link.has_message() {
+            self.pump().await?;
         }
+        Ok(self.link.take_message())
     }
-}
 
-impl Drop for UdpRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut c) = self.child_proc {
-            c.kill().unwrap();
-        }
+    fn report(&self) -> Option<String> {
+        Some(self.stats().to_string())
     }
 }
+
+pub type AsyncUdpRunner = AsyncRunner<AsyncUdpStreamWrapper>;
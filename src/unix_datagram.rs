@@ -1,133 +1,758 @@
+// Messages bigger than one datagram are sent as fragments, each starting with a header:
+//
+// | message id: u32 | fragment index: u32 | message length: u32 | payload |
+//
+// Before any of that the consumer sends the producer a bare ready datagram, and the producer
+// answers with its own once it has connected back. Each side checks the other's ready came from
+// the peer's socket file and from a process its peer policy allows. After that both sockets are
+// connected, and a connected unix datagram socket only takes datagrams from its peer.
+//
+// Unix datagrams aren't dropped or reordered, so fragments arrive in order and the receiver only
+// has to check each one carries on from the last. Fragments are read and written with recvmsg and
+// writev, so payloads go straight between the caller's buffer and ours.
+
 use std::{
-    os::unix::net::UnixDatagram,
-    process::{Child, Command},
-    thread::sleep,
-    time::{Duration, Instant},
+    io::{self, ErrorKind, IoSlice, IoSliceMut},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd},
+        unix::net::UnixDatagram,
+    },
+    path::{Path, PathBuf},
+    process::Child,
+    time::Duration,
 };
 
-use crate::{get_payload, ExecutionResult, KB};
+use tokio::io::Interest;
+
+use crate::async_transport::{wait_for_consumer_async, AsyncRunner, AsyncTransport};
+use crate::deadline;
+use crate::error::{IpcError, Result};
+use crate::peer_cred::{self, PeerPolicy, Sender};
+use crate::transport::{
+    endpoint_path, wait_for_consumer, NamedEndpoint, Runner, Transport, TransportConfig,
+};
+use crate::unix_stream::remove_stale_socket;
+
+const HEADER_LEN: usize = 12;
+// The kernel refuses a datagram within this many bytes of the send buffer's size
+const DATAGRAM_OVERHEAD: usize = 32;
+// Sent bare, before any fragments
+const READY: u8 = 1;
+
+struct FragmentHeader {
+    id: u32,
+    index: u32,
+    total_len: usize,
+}
+
+impl FragmentHeader {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.id.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.index.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.total_len as u32).to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN]) -> Self {
+        Self {
+            id: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            index: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            total_len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+        }
+    }
+}
 
-const MAX_CHUNK_SIZE: usize = 64 * KB;
-const UNIX_DATAGRAM_SOCKET_1: &str = "/tmp/unix_datagram1.sock";
-const UNIX_DATAGRAM_SOCKET_2: &str = "/tmp/unix_datagram2.sock";
+// Progress through the message whose fragments are arriving
+#[derive(Default)]
+struct Reassembly {
+    id: u32,
+    next_index: u32,
+    received: usize,
+    total_len: usize,
+}
+
+impl Reassembly {
+    // Take in a fragment of `size` bytes, header included, whose payload has been read in after
+    // what we already have. `truncated` is whether the payload was cut short to fit. Returns
+    // whether the message is now whole
+    fn push(
+        &mut self,
+        header: &[u8; HEADER_LEN],
+        size: usize,
+        truncated: bool,
+        max_message_size: usize,
+    ) -> Result<bool> {
+        if size < HEADER_LEN {
+            return Err(IpcError::protocol("Fragment too short for its header"));
+        }
+        let header = FragmentHeader::decode(header);
+        if truncated {
+            return Err(IpcError::protocol(format!(
+                "Fragment {} of message {} is bigger than the room left for it",
+                header.index, header.id
+            )));
+        }
+        if header.index == 0 && self.next_index == 0 {
+            if header.total_len > max_message_size {
+                return Err(IpcError::SizeMismatch {
+                    expected: max_message_size,
+                    actual: header.total_len,
+                });
+            }
+            self.id = header.id;
+            self.total_len = header.total_len;
+        } else if header.id != self.id || header.index != self.next_index {
+            return Err(IpcError::protocol(format!(
+                "Fragment {} of message {} arrived while expecting fragment {} of message {}",
+                header.index, header.id, self.next_index, self.id
+            )));
+        } else if header.total_len != self.total_len {
+            return Err(IpcError::protocol(format!(
+                "Fragment {} of message {} gives its length as {} bytes rather than {}",
+                header.index, header.id, header.total_len, self.total_len
+            )));
+        }
+        self.next_index += 1;
+        self.received += size - HEADER_LEN;
+        if self.received > self.total_len {
+            return Err(IpcError::protocol(format!(
+                "Message {} is longer than its {} bytes",
+                self.id, self.total_len
+            )));
+        }
+        Ok(self.received == self.total_len)
+    }
+}
+
+// The fragments `data` goes out as. An empty message still goes out as one empty fragment
+fn fragments(data: &[u8], fragment_size: usize) -> impl Iterator<Item = &[u8]> {
+    let empty = data.is_empty().then_some(data);
+    data.chunks(fragment_size).chain(empty)
+}
+
+// Check a ready datagram came from the peer's socket file, and from a peer the policy allows
+fn check_ready(sender: &Sender, peer_socket_path: &Path, policy: &PeerPolicy) -> Result<()> {
+    if sender.path.as_deref() != Some(peer_socket_path) {
+        return Err(IpcError::protocol(format!(
+            "Ready signal came from {:?} rather than the peer's socket {:?}",
+            sender.path, peer_socket_path
+        )));
+    }
+    let credentials = sender
+        .credentials
+        .ok_or_else(|| IpcError::protocol("Ready signal came without credentials"))?;
+    policy.check(&credentials)
+}
+
+fn check_message_size(data: &[u8], max_message_size: usize) -> Result<()> {
+    if data.len() > max_message_size || data.len() > u32::MAX as usize {
+        return Err(IpcError::SizeMismatch {
+            expected: max_message_size,
+            actual: data.len(),
+        });
+    }
+    Ok(())
+}
+
+// The most payload a fragment can carry, going by how big a datagram the socket will send
+fn fragment_size(socket: BorrowedFd<'_>) -> Result<usize> {
+    let mut sndbuf: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            &mut sndbuf as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    (sndbuf as usize)
+        .checked_sub(DATAGRAM_OVERHEAD + HEADER_LEN)
+        .filter(|size| *size > 0)
+        .ok_or_else(|| IpcError::protocol(format!("Send buffer of {} bytes is too small", sndbuf)))
+}
+
+// Write one fragment as a single datagram
+fn send_fragment(socket: BorrowedFd<'_>, header: &[u8], payload: &[u8]) -> io::Result<()> {
+    let iov = [IoSlice::new(header), IoSlice::new(payload)];
+    loop {
+        // IoSlice is guaranteed to match iovec on unix
+        let res = unsafe { libc::writev(socket.as_raw_fd(), iov.as_ptr().cast(), 2) };
+        if res >= 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+// Read one datagram, its header into `header` and its payload into `payload`, returning its size
+// and whether it was cut short to fit
+fn recv_fragment(
+    socket: BorrowedFd<'_>,
+    header: &mut [u8; HEADER_LEN],
+    payload: &mut [u8],
+) -> io::Result<(usize, bool)> {
+    let mut iov = [IoSliceMut::new(header), IoSliceMut::new(payload)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    // IoSliceMut is guaranteed to match iovec on unix
+    msg.msg_iov = iov.as_mut_ptr().cast();
+    msg.msg_iovlen = 2;
+    loop {
+        let res = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if res >= 0 {
+            return Ok((res as usize, msg.msg_flags & libc::MSG_TRUNC != 0));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
 
 pub struct UnixDatagramWrapper {
     pub socket: UnixDatagram,
-    pub peer_socket_path: String,
-    pub data_size: usize,
+    pub name: String,
+    pub socket_path: PathBuf,
+    pub peer_socket_path: PathBuf,
+    /// Most payload we put in one datagram, from the socket's send buffer size
+    pub fragment_size: usize,
+    next_id: u32,
+    buf: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+// Our socket path and the peer's, each side binds its own under the endpoint name
+fn socket_paths(name: &str, is_child: bool) -> Result<(PathBuf, PathBuf)> {
+    let consumer = endpoint_path(name, "-consumer.sock");
+    let producer = endpoint_path(name, "-producer.sock");
+    let (ours, theirs) = if is_child {
+        (consumer, producer)
+    } else {
+        (producer, consumer)
+    };
+    remove_stale_socket(&ours)?;
+    Ok((ours, theirs))
+}
+
+// Once bound, restrict the socket file and have each datagram carry its sender's credentials
+// until the handshake is done
+fn secure_socket(socket: BorrowedFd<'_>, path: &Path, socket_mode: u32) -> Result<()> {
+    peer_cred::restrict_socket(path, socket_mode)?;
+    peer_cred::pass_credentials(socket, true)
 }
 
 impl UnixDatagramWrapper {
-    pub fn new(is_child: bool, data_size: usize) -> Self {
-        let (socket_path, peer_socket_path) = if is_child {
-            (UNIX_DATAGRAM_SOCKET_1, UNIX_DATAGRAM_SOCKET_2)
-        } else {
-            (UNIX_DATAGRAM_SOCKET_2, UNIX_DATAGRAM_SOCKET_1)
-        };
-        let socket = UnixDatagram::bind(socket_path).unwrap();
+    pub fn new(
+        name: &str,
+        is_child: bool,
+        max_message_size: usize,
+        socket_mode: u32,
+    ) -> Result<Self> {
+        let (socket_path, peer_socket_path) = socket_paths(name, is_child)?;
+        let socket = UnixDatagram::bind(&socket_path)?;
+        secure_socket(socket.as_fd(), &socket_path, socket_mode)?;
+        let fragment_size = fragment_size(socket.as_fd())?;
 
-        Self {
+        Ok(Self {
             socket,
-            peer_socket_path: peer_socket_path.to_string(),
-            data_size,
-        }
+            name: name.to_string(),
+            socket_path,
+            peer_socket_path,
+            fragment_size,
+            next_id: 0,
+            buf: vec![0; max_message_size],
+            timeout: None,
+        })
     }
 
-    pub fn connect_to_peer(&self) {
-        self.socket.connect(&self.peer_socket_path).unwrap();
+    pub fn connect_to_peer(&self) -> Result<()> {
+        self.socket.connect(&self.peer_socket_path)?;
+        Ok(())
     }
 
-    pub fn send(&self, data: &Vec<u8>) {
-        for chunk in data.chunks(MAX_CHUNK_SIZE) {
-            loop {
-                match self.socket.send(chunk) {
-                    Ok(_) => break,
-                    Err(_) => continue,
+    // The producer's half of the handshake, which leaves us connected to the consumer
+    fn ready_from_consumer(
+        &self,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<()> {
+        // The consumer sends a ready datagram once its socket is bound
+        let socket = &self.socket;
+        socket.set_nonblocking(true)?;
+        wait_for_consumer(
+            child,
+            config.ready_timeout,
+            || match peer_cred::recv_from_sender(socket.as_fd(), &mut [0; 1]) {
+                Ok(sender) => {
+                    check_ready(&sender, &self.peer_socket_path, &config.peer_policy)?;
+                    Ok(true)
                 }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+                Err(e) => Err(e.into()),
+            },
+        )?;
+        self.connect_to_peer()?;
+        // The consumer sends nothing more until it has our ready, so anything else which came
+        // before we connected is from someone else
+        loop {
+            match socket.recv(&mut [0; 1]) {
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
             }
         }
+        socket.set_nonblocking(false)?;
+        peer_cred::pass_credentials(socket.as_fd(), false)?;
+        socket.send(&[READY])?;
+        Ok(())
+    }
+
+    // The consumer's half of the handshake
+    fn ready_from_producer(&self, config: &TransportConfig) -> Result<()> {
+        self.connect_to_peer()?;
+        self.socket.send(&[READY])?;
+        // Wait for the producer's ready, which tells us who it is
+        let socket = self.socket.as_fd();
+        deadline::wait_readable(socket, deadline::deadline(Some(config.ready_timeout)))?;
+        let sender = peer_cred::recv_from_sender(socket, &mut [0; 1])?;
+        check_ready(&sender, &self.peer_socket_path, &config.peer_policy)?;
+        peer_cred::pass_credentials(socket, false)?;
+        Ok(())
+    }
+}
+
+impl Transport for UnixDatagramWrapper {
+    const CONSUMER: &'static str = "unix-datagram";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    type Listener = UnixDatagramWrapper;
+
+    type Endpoint = NamedEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "Unix DATAGRAM Socket".to_string()
+    }
+
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        let is_child = false;
+        UnixDatagramWrapper::new(
+            &config.resolve_endpoint_name(),
+            is_child,
+            config.max_message_size,
+            config.socket_mode,
+        )
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<NamedEndpoint> {
+        Ok(NamedEndpoint {
+            name: listener.name.clone(),
+        })
+    }
+
+    fn accept(
+        mut listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        if let Err(e) = listener.ready_from_consumer(config, child) {
+            listener.close();
+            return Err(e);
+        }
+        Ok(listener)
+    }
+
+    fn connect(config: &TransportConfig, endpoint: &NamedEndpoint) -> Result<Self> {
+        let is_child = true;
+        let mut wrapper = UnixDatagramWrapper::new(
+            &endpoint.name,
+            is_child,
+            config.max_message_size,
+            config.socket_mode,
+        )?;
+        if let Err(e) = wrapper.ready_from_producer(config) {
+            wrapper.close();
+            return Err(e);
+        }
+        Ok(wrapper)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        check_message_size(data, self.buf.len())?;
+        let deadline = deadline::deadline(timeout);
+        self.next_id = self.next_id.wrapping_add(1);
+        for (index, fragment) in fragments(data, self.fragment_size).enumerate() {
+            let header = FragmentHeader {
+                id: self.next_id,
+                index: index as u32,
+                total_len: data.len(),
+            };
+            deadline::wait_writable(self.socket.as_fd(), deadline)?;
+            send_fragment(self.socket.as_fd(), &header.encode(), fragment)?;
+        }
+        Ok(())
     }
 
-    pub fn recv(&self) -> Vec<u8> {
-        let mut received_data = Vec::new();
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let deadline = deadline::deadline(timeout);
+        let max_message_size = self.buf.len();
+        let mut message = Reassembly::default();
+        let mut header = [0; HEADER_LEN];
         loop {
-            let mut buf = vec![0; MAX_CHUNK_SIZE];
-            let size = self.socket.recv(&mut buf).unwrap();
-            received_data.extend_from_slice(&buf[..size]);
-            if received_data.len() == self.data_size {
-                break;
+            // Once a fragment has been taken, the rest of the message would be read as the start
+            // of the next
+            deadline::wait_readable(self.socket.as_fd(), deadline).map_err(|e| {
+                if message.next_index > 0 {
+                    deadline::partway(e)
+                } else {
+                    e
+                }
+            })?;
+            let (size, truncated) = recv_fragment(
+                self.socket.as_fd(),
+                &mut header,
+                &mut self.buf[message.received..],
+            )?;
+            if message.push(&header, size, truncated, max_message_size)? {
+                return Ok(&self.buf[..message.total_len]);
             }
         }
-        received_data
+    }
+
+    // The socket we bound, and the peer's only if it died without removing it. A live peer's
+    // socket is still bound and is left alone
+    fn close(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+        let _ = remove_stale_socket(&self.peer_socket_path);
     }
 }
 
-pub struct UnixDatagramRunner {
-    child_proc: Option<Child>,
-    wrapper: UnixDatagramWrapper,
-    data_size: usize,
-    request_data: Vec<u8>,
-    #[allow(unused)]
-    response_data: Vec<u8>,
+pub type UnixDatagramRunner = Runner<UnixDatagramWrapper>;
+
+pub struct AsyncUnixDatagramWrapper {
+    pub socket: tokio::net::UnixDatagram,
+    pub name: String,
+    pub socket_path: PathBuf,
+    pub peer_socket_path: PathBuf,
+    /// Most payload we put in one datagram, from the socket's send buffer size
+    pub fragment_size: usize,
+    next_id: u32,
+    buf: Vec<u8>,
+    // Kept between calls so a recv cancelled partway through a message picks up where it stopped
+    message: Reassembly,
 }
 
-impl UnixDatagramRunner {
-    pub fn new(start_child: bool, data_size: usize) -> Self {
+impl AsyncUnixDatagramWrapper {
+    pub fn new(
+        name: &str,
+        is_child: bool,
+        max_message_size: usize,
+        socket_mode: u32,
+    ) -> Result<Self> {
+        let (socket_path, peer_socket_path) = socket_paths(name, is_child)?;
+        let socket = tokio::net::UnixDatagram::bind(&socket_path)?;
+        secure_socket(socket.as_fd(), &socket_path, socket_mode)?;
+        let fragment_size = fragment_size(socket.as_fd())?;
+
+        Ok(Self {
+            socket,
+            name: name.to_string(),
+            socket_path,
+            peer_socket_path,
+            fragment_size,
+            next_id: 0,
+            buf: vec![0; max_message_size],
+            message: Reassembly::default(),
+        })
+    }
+
+    pub fn connect_to_peer(&self) -> Result<()> {
+        self.socket.connect(&self.peer_socket_path)?;
+        Ok(())
+    }
+
+    async fn ready_from_consumer(
+        &self,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<()> {
+        // The consumer sends a ready datagram once its socket is bound
+        let socket = &self.socket;
+        let sender = wait_for_consumer_async(child, config.ready_timeout, async {
+            Ok(socket
+                .async_io(Interest::READABLE, || {
+                    peer_cred::recv_from_sender(socket.as_fd(), &mut [0; 1])
+                })
+                .await?)
+        })
+        .await?;
+        check_ready(&sender, &self.peer_socket_path, &config.peer_policy)?;
+        self.connect_to_peer()?;
+        // As with the blocking version, anything else which came before we connected is from
+        // someone else
+        loop {
+            match socket.try_recv(&mut [0; 1]) {
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        peer_cred::pass_credentials(socket.as_fd(), false)?;
+        socket.send(&[READY]).await?;
+        Ok(())
+    }
+
+    async fn ready_from_producer(&self, config: &TransportConfig) -> Result<()> {
+        self.connect_to_peer()?;
+        self.socket.send(&[READY]).await?;
+        // Wait for the producer's ready, which tells us who it is
+        let socket = &self.socket;
+        let sender = tokio::time::timeout(
+            config.ready_timeout,
+            socket.async_io(Interest::READABLE, || {
+                peer_cred::recv_from_sender(socket.as_fd(), &mut [0; 1])
+            }),
+        )
+        .await
+        .map_err(|_| IpcError::Timeout)??;
+        check_ready(&sender, &self.peer_socket_path, &config.peer_policy)?;
+        peer_cred::pass_credentials(socket.as_fd(), false)?;
+        Ok(())
+    }
+}
+
+impl AsyncTransport for AsyncUnixDatagramWrapper {
+    type Blocking = UnixDatagramWrapper;
+
+    type Listener = AsyncUnixDatagramWrapper;
+
+    async fn listen(config: &TransportConfig) -> Result<Self::Listener> {
         let is_child = false;
-        let wrapper = UnixDatagramWrapper::new(is_child, data_size);
-
-        let exe = crate::executable_path("unix_datagram_consumer");
-        let child_proc = if start_child {
-            let res = Some(
-                Command::new(exe)
-                    .args(&[data_size.to_string()])
-                    .spawn()
-                    .unwrap(),
-            );
-            // Awkward sleep to wait for consumer to be ready
-            sleep(Duration::from_secs(2));
-            res
-        } else {
-            None
-        };
+        AsyncUnixDatagramWrapper::new(
+            &config.resolve_endpoint_name(),
+            is_child,
+            config.max_message_size,
+            config.socket_mode,
+        )
+    }
 
-        wrapper.connect_to_peer();
+    fn endpoint(listener: &Self::Listener) -> Result<NamedEndpoint> {
+        Ok(NamedEndpoint {
+            name: listener.name.clone(),
+        })
+    }
 
-        let (request_data, response_data) = get_payload(data_size);
+    async fn accept(
+        mut listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        if let Err(e) = listener.ready_from_consumer(config, child).await {
+            listener.close();
+            return Err(e);
+        }
+        Ok(listener)
+    }
 
-        Self {
-            child_proc,
-            wrapper,
-            data_size,
-            request_data,
-            response_data,
+    async fn connect(config: &TransportConfig, endpoint: &NamedEndpoint) -> Result<Self> {
+        let is_child = true;
+        let mut wrapper = AsyncUnixDatagramWrapper::new(
+            &endpoint.name,
+            is_child,
+            config.max_message_size,
+            config.socket_mode,
+        )?;
+        if let Err(e) = wrapper.ready_from_producer(config).await {
+            wrapper.close();
+            return Err(e);
         }
+        Ok(wrapper)
     }
 
-    pub fn run(&mut self, n: usize, print: bool) {
-        let start = Instant::now();
-        for _ in 0..n {
-            self.wrapper.send(&self.request_data);
-            let _response = self.wrapper.recv();
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        check_message_size(data, self.buf.len())?;
+        self.next_id = self.next_id.wrapping_add(1);
+        for (index, fragment) in fragments(data, self.fragment_size).enumerate() {
+            let header = FragmentHeader {
+                id: self.next_id,
+                index: index as u32,
+                total_len: data.len(),
+            }
+            .encode();
+            let socket = &self.socket;
+            socket
+                .async_io(Interest::WRITABLE, || {
+                    send_fragment(socket.as_fd(), &header, fragment)
+                })
+                .await?;
         }
-        if print {
-            let elapsed = start.elapsed();
-            let res = ExecutionResult::new(
-                format!("Unix DATAGRAM Socket - {}KB", self.data_size / KB).to_string(),
-                elapsed,
-                n,
-            );
-            res.print_info();
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<&[u8]> {
+        let max_message_size = self.buf.len();
+        let mut header = [0; HEADER_LEN];
+        loop {
+            let socket = &self.socket;
+            let payload = &mut self.buf[self.message.received..];
+            let (size, truncated) = socket
+                .async_io(Interest::READABLE, || {
+                    recv_fragment(socket.as_fd(), &mut header, payload)
+                })
+                .await?;
+            if self
+                .message
+                .push(&header, size, truncated, max_message_size)?
+            {
+                let message = std::mem::take(&mut self.message);
+                return Ok(&self.buf[..message.total_len]);
+            }
         }
     }
+
+    // The socket we bound, and the peer's only if it died without removing it. A live peer's
+    // socket is still bound and is left alone
+    fn close(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+        let _ = remove_stale_socket(&self.peer_socket_path);
+    }
 }
 
-impl Drop for UnixDatagramRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut c) = self.child_proc {
-            c.kill().unwrap();
-        }
-        let _ = std::fs::remove_file(UNIX_DATAGRAM_SOCKET_1);
-        let _ = std::fs::remove_file(UNIX_DATAGRAM_SOCKET_2);
+pub type AsyncUnixDatagramRunner = AsyncRunner<AsyncUnixDatagramWrapper>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_MESSAGE_SIZE: usize = 100;
+
+    // Push a fragment carrying `payload` bytes
+    fn push(
+        message: &mut Reassembly,
+        id: u32,
+        index: u32,
+        total_len: usize,
+        payload: usize,
+    ) -> Result<bool> {
+        let header = FragmentHeader {
+            id,
+            index,
+            total_len,
+        };
+        message.push(
+            &header.encode(),
+            HEADER_LEN + payload,
+            false,
+            MAX_MESSAGE_SIZE,
+        )
+    }
+
+    #[test]
+    fn fragments_in_order_make_a_whole_message() {
+        let mut message = Reassembly::default();
+        assert!(!push(&mut message, 7, 0, 30, 10).unwrap());
+        assert!(!push(&mut message, 7, 1, 30, 10).unwrap());
+        assert!(push(&mut message, 7, 2, 30, 10).unwrap());
+
+        let mut empty = Reassembly::default();
+        assert!(push(&mut empty, 8, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn fragment_out_of_order_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        push(&mut message, 7, 0, 30, 10).unwrap();
+        let skipped = push(&mut message, 7, 2, 30, 10);
+        assert!(matches!(skipped, Err(IpcError::Protocol(_))));
+
+        let mut message = Reassembly::default();
+        let first_missing = push(&mut message, 7, 1, 30, 10);
+        assert!(matches!(first_missing, Err(IpcError::Protocol(_))));
+    }
+
+    #[test]
+    fn duplicated_fragment_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        push(&mut message, 7, 0, 30, 10).unwrap();
+        push(&mut message, 7, 1, 30, 10).unwrap();
+        let again = push(&mut message, 7, 1, 30, 10);
+        assert!(matches!(again, Err(IpcError::Protocol(_))));
+    }
+
+    #[test]
+    fn fragment_of_another_message_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        push(&mut message, 7, 0, 30, 10).unwrap();
+        let other = push(&mut message, 8, 1, 30, 10);
+        assert!(matches!(other, Err(IpcError::Protocol(_))));
+    }
+
+    #[test]
+    fn truncated_fragment_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        let header = FragmentHeader {
+            id: 7,
+            index: 0,
+            total_len: 30,
+        };
+        let truncated = message.push(&header.encode(), HEADER_LEN + 10, true, MAX_MESSAGE_SIZE);
+        assert!(matches!(truncated, Err(IpcError::Protocol(_))));
+    }
+
+    #[test]
+    fn fragment_shorter_than_its_header_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        let header = FragmentHeader {
+            id: 7,
+            index: 0,
+            total_len: 30,
+        };
+        let short = message.push(&header.encode(), HEADER_LEN - 1, false, MAX_MESSAGE_SIZE);
+        assert!(matches!(short, Err(IpcError::Protocol(_))));
+    }
+
+    #[test]
+    fn message_over_the_maximum_is_a_size_mismatch() {
+        let mut message = Reassembly::default();
+        let oversized = push(&mut message, 7, 0, MAX_MESSAGE_SIZE + 1, 10);
+        assert!(matches!(
+            oversized,
+            Err(IpcError::SizeMismatch {
+                expected: MAX_MESSAGE_SIZE,
+                actual,
+            }) if actual == MAX_MESSAGE_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn later_fragment_changing_the_length_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        push(&mut message, 7, 0, 30, 10).unwrap();
+        let changed = push(&mut message, 7, 1, 40, 10);
+        assert!(matches!(changed, Err(IpcError::Protocol(_))));
+    }
+
+    #[test]
+    fn payload_past_the_length_is_a_protocol_error() {
+        let mut message = Reassembly::default();
+        push(&mut message, 7, 0, 30, 20).unwrap();
+        let overflowing = push(&mut message, 7, 1, 30, 20);
+        assert!(matches!(overflowing, Err(IpcError::Protocol(_))));
     }
 }
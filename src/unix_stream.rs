@@ -1,99 +1,441 @@
 use std::{
-    io::{Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
-    process::{Child, Command},
-    thread::sleep,
-    time::{Duration, Instant},
+    io::{self, ErrorKind, IoSlice, IoSliceMut, Read, Write},
+    mem::size_of,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    process::Child,
+    time::Duration,
 };
 
-use crate::{get_payload, ExecutionResult, KB};
+use crate::async_transport::{wait_for_consumer_async, AsyncRunner, AsyncTransport};
+use crate::deadline::{self, WithDeadline};
+use crate::error::{IpcError, Result};
+use crate::framing::{
+    read_frame, read_frame_async, write_frame, write_frame_async, FrameProgress, HEADER_LEN,
+};
+use crate::peer_cred::{self, PeerCredentials, PeerPolicy};
+use crate::transport::{
+    endpoint_path, wait_for_consumer, NamedEndpoint, Runner, Transport, TransportConfig,
+};
+
+/// Most file descriptors which can go with one message
+pub const MAX_FDS: usize = 16;
+
+// Control message buffer, as words so it's aligned for cmsghdr. Room for the header and MAX_FDS
+// descriptors with some to spare
+const CONTROL_WORDS: usize = 2 + MAX_FDS;
+
+fn socket_path(name: &str) -> PathBuf {
+    endpoint_path(name, ".sock")
+}
 
-const UNIX_SOCKET_PATH: &str = "/tmp/unix_stream.sock";
+// A socket file left behind by a run which didn't get to clean up would make bind fail. Nothing
+// is bound to it, so connecting is refused and it can go. A live datagram socket fails with a
+// different error, so this works for both kinds
+pub(crate) fn remove_stale_socket(path: &Path) -> Result<()> {
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if !is_socket {
+        return Ok(());
+    }
+    if let Err(e) = UnixStream::connect(path) {
+        if e.kind() == ErrorKind::ConnectionRefused {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
 
 pub struct UnixStreamWrapper {
     pub stream: UnixStream,
+    pub path: PathBuf,
+    // Whether this side bound `path`, only then is it ours to remove
+    bound: bool,
+    buf: Vec<u8>,
+    timeout: Option<Duration>,
 }
 
 impl UnixStreamWrapper {
-    pub fn from_listener(listener: UnixListener) -> Self {
-        let (stream, _socket) = listener.accept().unwrap();
-        Self { stream }
+    pub fn from_listener(
+        listener: UnixListener,
+        path: PathBuf,
+        max_message_size: usize,
+    ) -> Result<Self> {
+        let (stream, _socket) = listener.accept()?;
+        Ok(Self::from_stream(stream, path, true, max_message_size))
+    }
+
+    fn from_stream(
+        stream: UnixStream,
+        path: PathBuf,
+        bound: bool,
+        max_message_size: usize,
+    ) -> Self {
+        Self {
+            stream,
+            path,
+            bound,
+            buf: vec![0; max_message_size],
+            timeout: None,
+        }
+    }
+
+    pub fn unix_connect(name: &str, max_message_size: usize) -> Result<Self> {
+        let path = socket_path(name);
+        let stream = UnixStream::connect(&path)?;
+        Ok(Self::from_stream(stream, path, false, max_message_size))
+    }
+
+    /// Who is on the other end, as of when the connection was made
+    pub fn peer_credentials(&self) -> Result<PeerCredentials> {
+        peer_cred::stream_peer(self.stream.as_fd())
+    }
+
+    // Hand the connection back if `policy` allows the peer, otherwise close it
+    fn allowed_by(mut self, policy: &PeerPolicy) -> Result<Self> {
+        match self.peer_credentials().and_then(|peer| policy.check(&peer)) {
+            Ok(()) => Ok(self),
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+
+    /// Send `data` as one message with `fds` attached, for the peer to pick up with
+    /// [`UnixStreamWrapper::recv_with_fds`]. The peer gets its own descriptors for the same files,
+    /// ours stay open. If the peer reads the message with plain `recv` the descriptors are closed
+    pub fn send_with_fds(&mut self, data: &[u8], fds: &[BorrowedFd<'_>]) -> Result<()> {
+        if fds.len() > MAX_FDS {
+            return Err(IpcError::protocol(format!(
+                "Can't send {} descriptors with a message, the most is {}",
+                fds.len(),
+                MAX_FDS
+            )));
+        }
+        if data.len() > self.buf.len() || data.len() > u32::MAX as usize {
+            return Err(IpcError::SizeMismatch {
+                expected: self.buf.len(),
+                actual: data.len(),
+            });
+        }
+        let deadline = deadline::deadline(self.timeout);
+        let header = (data.len() as u32).to_le_bytes();
+        let iov = [IoSlice::new(&header), IoSlice::new(data)];
+        let mut control = [0usize; CONTROL_WORDS];
+
+        // IoSlice is guaranteed to match iovec on unix
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iov.as_ptr() as *mut libc::iovec;
+        msg.msg_iovlen = iov.len() as _;
+        if !fds.is_empty() {
+            let fds_len = (fds.len() * size_of::<RawFd>()) as libc::c_uint;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                for (i, fd) in fds.iter().enumerate() {
+                    data.add(i).write_unaligned(fd.as_raw_fd());
+                }
+            }
+        }
+
+        deadline::wait_writable(self.stream.as_fd(), deadline)?;
+        let sent = retry_interrupted(|| unsafe {
+            libc::sendmsg(self.stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+        })?;
+
+        // The descriptors went with the first byte, whatever didn't fit goes out as plain writes
+        if sent == HEADER_LEN + data.len() {
+            return Ok(());
+        }
+        let mut stream = WithDeadline::new(&mut self.stream, deadline::remaining(deadline)?);
+        if sent < HEADER_LEN {
+            stream.write_all(&header[sent..])?;
+            stream.write_all(data)?;
+        } else {
+            stream.write_all(&data[sent - HEADER_LEN..])?;
+        }
+        Ok(())
     }
 
-    pub fn unix_connect() -> Self {
-        let stream = UnixStream::connect(UNIX_SOCKET_PATH).unwrap();
-        Self { stream }
+    /// Wait for a message, returning it with any descriptors sent with it by
+    /// [`UnixStreamWrapper::send_with_fds`]. They're ours to close, and are close-on-exec
+    pub fn recv_with_fds(&mut self) -> Result<(&[u8], Vec<OwnedFd>)> {
+        let deadline = deadline::deadline(self.timeout);
+        let mut header = [0; HEADER_LEN];
+        let mut control = [0usize; CONTROL_WORDS];
+        let mut iov = [IoSliceMut::new(&mut header)];
+
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iov.as_mut_ptr().cast();
+        msg.msg_iovlen = iov.len() as _;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = size_of::<[usize; CONTROL_WORDS]>() as _;
+
+        // The kernel ends a read at a message which carries descriptors, so they come with the
+        // first bytes of the header
+        deadline::wait_readable(self.stream.as_fd(), deadline)?;
+        let read = retry_interrupted(|| unsafe {
+            libc::recvmsg(self.stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+        })?;
+
+        // Take ownership of the descriptors before anything else can fail, so none leak
+        let mut fds = Vec::new();
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for i in 0..len / size_of::<RawFd>() {
+                        fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        if read == 0 {
+            return Err(IpcError::PeerGone);
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(IpcError::protocol(format!(
+                "More than {} descriptors came with a message, the rest were closed",
+                MAX_FDS
+            )));
+        }
+
+        let mut stream = WithDeadline::new(&mut self.stream, deadline::remaining(deadline)?);
+        stream.read_exact(&mut header[read..])?;
+        let len = u32::from_le_bytes(header) as usize;
+        if len > self.buf.len() {
+            return Err(IpcError::SizeMismatch {
+                expected: self.buf.len(),
+                actual: len,
+            });
+        }
+        stream.read_exact(&mut self.buf[..len])?;
+        Ok((&self.buf[..len], fds))
     }
 }
 
-pub struct UnixStreamRunner {
-    child_proc: Option<Child>,
-    wrapper: UnixStreamWrapper,
-    data_size: usize,
-    request_data: Vec<u8>,
-    response_data: Vec<u8>,
+// Run a syscall returning a count or -1, again if a signal interrupted it
+fn retry_interrupted(mut call: impl FnMut() -> isize) -> io::Result<usize> {
+    loop {
+        let res = call();
+        if res >= 0 {
+            return Ok(res as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
 }
 
-impl UnixStreamRunner {
-    pub fn new(start_child: bool, data_size: usize) -> Self {
-        let unix_listener = UnixListener::bind(UNIX_SOCKET_PATH).unwrap();
-        let exe = crate::executable_path("unix_stream_consumer");
-        let child_proc = if start_child {
-            let res = Some(
-                Command::new(exe)
-                    .args(&[data_size.to_string()])
-                    .spawn()
-                    .unwrap(),
-            );
-            // Awkward sleep to wait for consumer to be ready
-            sleep(Duration::from_secs(2));
-            res
-        } else {
-            None
-        };
+impl Transport for UnixStreamWrapper {
+    const CONSUMER: &'static str = "unix-stream";
+
+    const VARIABLE_LENGTH: bool = true;
+
+    // The socket and the endpoint name it was bound under
+    type Listener = (UnixListener, String);
+
+    type Endpoint = NamedEndpoint;
+
+    fn name(_config: &TransportConfig) -> String {
+        "Unix TCP Socket".to_string()
+    }
 
-        let wrapper = UnixStreamWrapper::from_listener(unix_listener);
+    fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        let name = config.resolve_endpoint_name();
+        let path = socket_path(&name);
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        peer_cred::restrict_socket(&path, config.socket_mode)?;
+        Ok((listener, name))
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<NamedEndpoint> {
+        Ok(NamedEndpoint {
+            name: listener.1.clone(),
+        })
+    }
 
-        let (request_data, response_data) = get_payload(data_size);
+    fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let (listener, name) = listener;
+        // The consumer connecting is its ready signal
+        listener.set_nonblocking(true)?;
+        let mut stream = None;
+        wait_for_consumer(child, config.ready_timeout, || match listener.accept() {
+            Ok((accepted, _socket)) => {
+                stream = Some(accepted);
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        })?;
+        let stream = stream.unwrap();
+        stream.set_nonblocking(false)?;
+        let wrapper = UnixStreamWrapper::from_stream(
+            stream,
+            socket_path(&name),
+            true,
+            config.max_message_size,
+        );
+        wrapper.allowed_by(&config.peer_policy)
+    }
 
+    fn connect(config: &TransportConfig, endpoint: &NamedEndpoint) -> Result<Self> {
+        UnixStreamWrapper::unix_connect(&endpoint.name, config.max_message_size)?
+            .allowed_by(&config.peer_policy)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let mut stream = WithDeadline::new(&mut self.stream, timeout);
+        write_frame(&mut stream, data, self.buf.len())
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<&[u8]> {
+        let mut stream = WithDeadline::new(&mut self.stream, timeout);
+        let len = read_frame(&mut stream, &mut self.buf)?;
+        Ok(&self.buf[..len])
+    }
+
+    fn close(&mut self) {
+        if self.bound {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub type UnixStreamRunner = Runner<UnixStreamWrapper>;
+
+pub struct AsyncUnixStreamWrapper {
+    pub stream: tokio::net::UnixStream,
+    pub path: PathBuf,
+    // Whether this side bound `path`, only then is it ours to remove
+    bound: bool,
+    buf: Vec<u8>,
+    progress: FrameProgress,
+}
+
+impl AsyncUnixStreamWrapper {
+    fn from_stream(
+        stream: tokio::net::UnixStream,
+        path: PathBuf,
+        bound: bool,
+        max_message_size: usize,
+    ) -> Self {
         Self {
-            child_proc,
-            wrapper,
-            data_size,
-            request_data,
-            response_data,
+            stream,
+            path,
+            bound,
+            buf: vec![0; max_message_size],
+            progress: FrameProgress::default(),
         }
     }
 
-    pub fn run(&mut self, n: usize, print: bool) {
-        let start = Instant::now();
-        let mut buf = vec![0; self.data_size];
-        for _ in 0..n {
-            self.wrapper.stream.write(&self.request_data).unwrap();
-            self.wrapper.stream.read_exact(&mut buf).unwrap();
+    /// Who is on the other end, as of when the connection was made
+    pub fn peer_credentials(&self) -> Result<PeerCredentials> {
+        peer_cred::stream_peer(self.stream.as_fd())
+    }
 
-            #[cfg(debug_assertions)]
-            if buf.ne(&self.response_data) {
-                panic!("Sent request didn't get response")
+    // Hand the connection back if `policy` allows the peer, otherwise close it
+    fn allowed_by(mut self, policy: &PeerPolicy) -> Result<Self> {
+        match self.peer_credentials().and_then(|peer| policy.check(&peer)) {
+            Ok(()) => Ok(self),
+            Err(e) => {
+                self.close();
+                Err(e)
             }
         }
-        if print {
-            let elapsed = start.elapsed();
-            let res = ExecutionResult::new(
-                format!("Unix TCP Socket - {}KB", self.data_size / KB).to_string(),
-                elapsed,
-                n,
-            );
-            res.print_info();
-        }
     }
 }
 
-impl Drop for UnixStreamRunner {
-    fn drop(&mut self) {
-        if let Some(ref mut c) = self.child_proc {
-            c.kill().unwrap();
+impl AsyncTransport for AsyncUnixStreamWrapper {
+    type Blocking = UnixStreamWrapper;
+
+    // The socket and the endpoint name it was bound under
+    type Listener = (tokio::net::UnixListener, String);
+
+    async fn listen(config: &TransportConfig) -> Result<Self::Listener> {
+        let name = config.resolve_endpoint_name();
+        let path = socket_path(&name);
+        remove_stale_socket(&path)?;
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        peer_cred::restrict_socket(&path, config.socket_mode)?;
+        Ok((listener, name))
+    }
+
+    fn endpoint(listener: &Self::Listener) -> Result<NamedEndpoint> {
+        Ok(NamedEndpoint {
+            name: listener.1.clone(),
+        })
+    }
+
+    async fn accept(
+        listener: Self::Listener,
+        config: &TransportConfig,
+        child: Option<&mut Child>,
+    ) -> Result<Self> {
+        let (listener, name) = listener;
+        // The consumer connecting is its ready signal
+        let (stream, _socket) = wait_for_consumer_async(child, config.ready_timeout, async {
+            Ok(listener.accept().await?)
+        })
+        .await?;
+        let wrapper = AsyncUnixStreamWrapper::from_stream(
+            stream,
+            socket_path(&name),
+            true,
+            config.max_message_size,
+        );
+        wrapper.allowed_by(&config.peer_policy)
+    }
+
+    async fn connect(config: &TransportConfig, endpoint: &NamedEndpoint) -> Result<Self> {
+        let path = socket_path(&endpoint.name);
+        let stream = tokio::net::UnixStream::connect(&path).await?;
+        AsyncUnixStreamWrapper::from_stream(stream, path, false, config.max_message_size)
+            .allowed_by(&config.peer_policy)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        write_frame_async(&mut self.stream, data, self.buf.len()).await
+    }
+
+    async fn recv(&mut self) -> Result<&[u8]> {
+        let len = read_frame_async(&mut self.stream, &mut self.buf, &mut self.progress).await?;
+        Ok(&self.buf[..len])
+    }
+
+    fn close(&mut self) {
+        if self.bound {
+            let _ = std::fs::remove_file(&self.path);
         }
-        let _ = std::fs::remove_file(UNIX_SOCKET_PATH);
     }
 }
+
+pub type AsyncUnixStreamRunner = AsyncRunner<AsyncUnixStreamWrapper>;
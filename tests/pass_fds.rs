@@ -0,0 +1,155 @@
+// Passing file descriptors with UnixStreamWrapper::send_with_fds and recv_with_fds. The round trip
+// test hands memfds to a child process, which is this test binary run again for just that test
+// with the socket's name in CHILD_ENV, and has it send back what it reads from each.
+
+use ipc::error::{IpcError, Result};
+use ipc::transport::Transport;
+use ipc::unix_stream::{UnixStreamWrapper, MAX_FDS};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::Command;
+use std::time::Duration;
+
+const CHILD_ENV: &str = "IPC_PASS_FDS_SOCKET";
+const CONTENTS: [&[u8]; 3] = [b"First memfd", b"Second memfd", b"Third memfd"];
+const MAX_MESSAGE_SIZE: usize = 1024;
+// Long enough for the child to start, short enough that a broken test fails rather than hangs
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn memfds_round_trip_through_a_child() -> Result<()> {
+    match std::env::var(CHILD_ENV) {
+        Ok(name) => child(&name),
+        Err(_) => parent(),
+    }
+}
+
+fn parent() -> Result<()> {
+    let name = format!("ipc-pass-fds-test-{}", std::process::id());
+    let path = std::env::temp_dir().join(format!("{}.sock", name));
+    let listener = UnixListener::bind(&path)?;
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(["--exact", "memfds_round_trip_through_a_child"])
+        .env(CHILD_ENV, &name)
+        .spawn()?;
+    let mut channel = UnixStreamWrapper::from_listener(listener, path, MAX_MESSAGE_SIZE)?;
+    channel.set_timeout(Some(TIMEOUT));
+
+    let memfds = CONTENTS
+        .iter()
+        .map(|contents| memfd(contents))
+        .collect::<Result<Vec<_>>>()?;
+    let fds: Vec<_> = memfds.iter().map(|memfd| memfd.as_fd()).collect();
+    channel.send_with_fds(b"memfds", &fds)?;
+
+    let mut read_by_child = Vec::new();
+    for _ in CONTENTS {
+        read_by_child.push(channel.recv()?.to_vec());
+    }
+    channel.close();
+    let status = child.wait()?;
+    assert!(status.success(), "The child failed: {}", status);
+    assert_eq!(read_by_child, CONTENTS);
+    Ok(())
+}
+
+fn child(name: &str) -> Result<()> {
+    let mut channel = UnixStreamWrapper::unix_connect(name, MAX_MESSAGE_SIZE)?;
+    channel.set_timeout(Some(TIMEOUT));
+    let (message, fds) = channel.recv_with_fds()?;
+    assert_eq!(message, b"memfds");
+    assert_eq!(fds.len(), CONTENTS.len(), "Expected a descriptor per memfd");
+
+    for fd in fds {
+        let mut memfd = File::from(fd);
+        // The file offset is shared with the parent's descriptor, which left it at the end
+        memfd.rewind()?;
+        let mut contents = Vec::new();
+        memfd.read_to_end(&mut contents)?;
+        channel.send(&contents)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn more_than_max_fds_are_refused() -> Result<()> {
+    let (mut sender, _receiver) = connected("refused")?;
+    let memfd = memfd(b"")?;
+    let fds = vec![memfd.as_fd(); MAX_FDS + 1];
+    let sent = sender.send_with_fds(b"too many", &fds);
+    sender.close();
+    assert!(matches!(sent, Err(IpcError::Protocol(_))), "Got {:?}", sent);
+    Ok(())
+}
+
+#[test]
+fn descriptors_cut_short_are_an_error() -> Result<()> {
+    let (mut receiver, raw) = connected("truncated")?;
+    receiver.set_timeout(Some(TIMEOUT));
+    // Far more than the receiver has room for, which the kernel closes and flags with MSG_CTRUNC
+    let memfd = memfd(b"")?;
+    let fds = vec![memfd.as_fd(); 8 * MAX_FDS];
+    send_raw_fds(&raw, &0u32.to_le_bytes(), &fds)?;
+    let received = receiver.recv_with_fds().map(|(_, fds)| fds.len());
+    receiver.close();
+    assert!(
+        matches!(&received, Err(IpcError::Protocol(msg)) if msg.contains("descriptors")),
+        "Got {:?}",
+        received
+    );
+    Ok(())
+}
+
+// A wrapper on the listening side of a socket and a plain stream connected to it. Closing the
+// wrapper removes the socket file
+fn connected(test: &str) -> Result<(UnixStreamWrapper, UnixStream)> {
+    let path =
+        std::env::temp_dir().join(format!("ipc-pass-fds-{}-{}.sock", test, std::process::id()));
+    let listener = UnixListener::bind(&path)?;
+    let stream = UnixStream::connect(&path)?;
+    let wrapper = UnixStreamWrapper::from_listener(listener, path, MAX_MESSAGE_SIZE)?;
+    Ok((wrapper, stream))
+}
+
+// Send `data` with `fds` in one sendmsg, without the checks send_with_fds makes
+fn send_raw_fds(stream: &UnixStream, data: &[u8], fds: &[BorrowedFd<'_>]) -> Result<()> {
+    let fds_len = (fds.len() * size_of::<RawFd>()) as libc::c_uint;
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    // As words so it's aligned for cmsghdr
+    let mut control = vec![0usize; space.div_ceil(size_of::<usize>())];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+        for (i, fd) in fds.iter().enumerate() {
+            data.add(i).write_unaligned(fd.as_raw_fd());
+        }
+    }
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn memfd(contents: &[u8]) -> Result<File> {
+    let fd = unsafe { libc::memfd_create(c"ipc-pass-fds-test".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut memfd = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    memfd.write_all(contents)?;
+    Ok(memfd)
+}